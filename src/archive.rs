@@ -0,0 +1,557 @@
+//! low level access to the members of a .pncsum archive, for the operations that
+//! need to work in place instead of streaming through them
+//!
+//! archives are newc cpio, or pax tar, told apart by their magic bytes. Tar
+//! members are named after the files alone, so that any tar tool extracts them
+//! in place, and the packed file carries its hash in `user.ncsum.` extended
+//! attributes, stored as pax records
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Chain, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use cpio::{write_cpio, NewcBuilder};
+
+const HEADER_LEN: u64 = 110;
+const TRAILER_NAME: &str = "TRAILER!!!";
+const BLOCK_LEN: u64 = 512;
+
+/// the largest member a newc archive can hold, its size having 8 hex digits
+pub const NEWC_MAX_SIZE: u64 = u32::MAX as u64;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Container {
+    /// newc cpio, whose members can't be larger than 4 GiB
+    #[default]
+    Cpio,
+    /// pax tar, which most archive tools understand
+    Tar,
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Container::Cpio => f.write_str("cpio"),
+            Container::Tar => f.write_str("tar"),
+        }
+    }
+}
+
+impl Container {
+    /// tells the container of an archive from its first block
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"070701") {
+            Some(Container::Cpio)
+        } else if head.len() >= 262 && &head[257..262] == b"ustar" {
+            Some(Container::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// cpio, unless a member of `size` bytes needs tar
+    pub fn fitting(size: u64) -> Self {
+        match size > NEWC_MAX_SIZE {
+            true => Container::Tar,
+            false => Container::Cpio,
+        }
+    }
+
+    /// the container of an archive file
+    pub fn of(fd: &mut (impl Read + Seek)) -> Result<Self, std::io::Error> {
+        let mut head = Vec::new();
+
+        fd.seek(SeekFrom::Start(0))?;
+        (&mut *fd).take(BLOCK_LEN).read_to_end(&mut head)?;
+
+        Self::detect(&head).ok_or_else(|| invalid("Invalid magic number"))
+    }
+}
+
+/// a member to write into a new archive
+pub struct Input<'a> {
+    pub name: &'a str,
+    /// pax records describing the member, only tar keeps them
+    pub records: Vec<(&'a str, String)>,
+    pub data: File,
+}
+
+pub struct Member {
+    pub name: String,
+    pub header_offset: u64,
+    pub data_offset: u64,
+    pub size: u64,
+}
+
+impl Member {
+    pub fn is_trailer(&self) -> bool {
+        self.name == TRAILER_NAME
+    }
+
+    /// whether the member holds the packed file itself, rather than data about it
+    pub fn is_payload(&self) -> bool {
+        !self.is_trailer() && is_payload_name(&self.name)
+    }
+}
+
+/// whether a member with this name holds the packed file, rather than its
+/// description, recovery data or signature
+pub fn is_payload_name(name: &str) -> bool {
+    !name.ends_with(".ncsum") && !name.ends_with(".rncsum") && !name.ends_with(".sncsum")
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn too_large(name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{name} is too large for a newc archive"),
+    )
+}
+
+fn align(offset: u64) -> u64 {
+    (offset + 3) & !3
+}
+
+fn align_block(offset: u64) -> u64 {
+    offset.div_ceil(BLOCK_LEN) * BLOCK_LEN
+}
+
+fn hex_field(header: &[u8], field: usize) -> Result<u64, std::io::Error> {
+    // every field after the 6 bytes magic number is 8 hex digits long
+    let start = 6 + field * 8;
+    let text = std::str::from_utf8(&header[start..start + 8]).unwrap_or("");
+
+    u64::from_str_radix(text, 16).map_err(|_| invalid("Invalid header field"))
+}
+
+/// lists the members of an archive, the trailer included, which for tar stands
+/// for the end of archive blocks
+pub fn members(fd: &mut (impl Read + Seek)) -> Result<Vec<Member>, std::io::Error> {
+    match Container::of(fd)? {
+        Container::Cpio => cpio_members(fd),
+        Container::Tar => tar_members(fd),
+    }
+}
+
+fn cpio_members(fd: &mut (impl Read + Seek)) -> Result<Vec<Member>, std::io::Error> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        fd.seek(SeekFrom::Start(offset))?;
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        fd.read_exact(&mut header)?;
+
+        if &header[..6] != b"070701" {
+            return Result::Err(invalid("Invalid magic number"));
+        }
+
+        let size = hex_field(&header, 6)?;
+        let name_len = hex_field(&header, 11)?;
+
+        let mut name = vec![0u8; name_len as usize];
+        fd.read_exact(&mut name)?;
+
+        let name = String::from_utf8_lossy(&name)
+            .trim_end_matches('\0')
+            .to_string();
+        let data_offset = align(offset + HEADER_LEN + name_len);
+
+        let member = Member {
+            name,
+            header_offset: offset,
+            data_offset,
+            size,
+        };
+
+        offset = align(data_offset + size);
+
+        let is_trailer = member.is_trailer();
+        members.push(member);
+
+        if is_trailer {
+            return Result::Ok(members);
+        }
+    }
+}
+
+fn tar_members(fd: &mut (impl Read + Seek)) -> Result<Vec<Member>, std::io::Error> {
+    let mut members = Vec::new();
+    let mut end = 0;
+
+    fd.seek(SeekFrom::Start(0))?;
+
+    for entry in tar::Archive::new(&mut *fd).entries_with_seek()? {
+        let entry = entry?;
+
+        members.push(Member {
+            name: entry.path()?.to_string_lossy().to_string(),
+            header_offset: entry.raw_header_position(),
+            data_offset: entry.raw_file_position(),
+            size: entry.size(),
+        });
+
+        end = align_block(entry.raw_file_position() + entry.size());
+    }
+
+    members.push(Member {
+        name: TRAILER_NAME.to_string(),
+        header_offset: end,
+        data_offset: end,
+        size: 0,
+    });
+
+    Result::Ok(members)
+}
+
+/// the name of a member inside a tar archive
+fn tar_name(name: &str) -> String {
+    match Path::new(name).file_name() {
+        Some(n) => n.to_string_lossy().to_string(),
+        None => name.to_string(),
+    }
+}
+
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &str) {
+    // the length of a record counts its own digits
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+
+    while len.to_string().len() + rest != len {
+        len = len.to_string().len() + rest;
+    }
+
+    pax.extend(format!("{len} {key}={value}\n").as_bytes());
+}
+
+fn ustar_header(name: &str, size: u64, entry_type: tar::EntryType) -> tar::Header {
+    let mut header = tar::Header::new_ustar();
+
+    // long names are cut, readers take them from the pax records
+    let field = &mut header.as_old_mut().name;
+    let len = name.len().min(field.len());
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_uid(1000);
+    header.set_cksum();
+
+    header
+}
+
+/// the blocks heading a tar member, a pax header carrying its name, its size and
+/// `records`, then a ustar header
+///
+/// the size is zero padded, so that the blocks of a member keep their length
+/// whatever its size, and can be rewritten in place once it is known
+pub fn tar_header(
+    name: &str,
+    size: u64,
+    records: &[(&str, String)],
+) -> Result<Vec<u8>, std::io::Error> {
+    let name = tar_name(name);
+    let mut pax = Vec::new();
+
+    pax_record(&mut pax, "path", &name);
+    pax_record(&mut pax, "size", &format!("{size:020}"));
+
+    for (key, value) in records {
+        pax_record(&mut pax, key, value);
+    }
+
+    let mut blocks = ustar_header("././@PaxHeader", pax.len() as u64, tar::EntryType::XHeader)
+        .as_bytes()
+        .to_vec();
+
+    blocks.extend(&pax);
+    blocks.resize(align_block(blocks.len() as u64) as usize, 0);
+    blocks.extend(ustar_header(&name, size, tar::EntryType::Regular).as_bytes());
+
+    Result::Ok(blocks)
+}
+
+/// pads the data of a tar member up to the next block
+pub fn tar_padding(size: u64) -> Vec<u8> {
+    vec![0; (align_block(size) - size) as usize]
+}
+
+/// the blocks ending a tar archive
+pub fn tar_end() -> Vec<u8> {
+    vec![0; 2 * BLOCK_LEN as usize]
+}
+
+fn write_tar_member(
+    output: &mut impl Write,
+    name: &str,
+    records: &[(&str, String)],
+    data: &mut impl Read,
+    size: u64,
+) -> Result<(), std::io::Error> {
+    output.write_all(&tar_header(name, size, records)?)?;
+
+    if std::io::copy(&mut data.take(size), output)? != size {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{name} is shorter than expected"),
+        ));
+    }
+
+    output.write_all(&tar_padding(size))
+}
+
+/// writes an archive holding `inputs`, in that order
+pub fn write(
+    container: Container,
+    inputs: Vec<Input>,
+    output: &mut impl Write,
+) -> Result<(), std::io::Error> {
+    match container {
+        Container::Cpio => {
+            for input in inputs.iter() {
+                if input.data.metadata()?.len() > NEWC_MAX_SIZE {
+                    return Result::Err(too_large(input.name));
+                }
+            }
+
+            let inputs = inputs.into_iter().map(|i| {
+                let builder = NewcBuilder::new(i.name).uid(1000).mode(0o100644);
+
+                (builder, i.data)
+            });
+
+            write_cpio(inputs, &mut *output)?;
+        }
+        Container::Tar => {
+            for mut input in inputs {
+                let size = input.data.metadata()?.len();
+
+                write_tar_member(
+                    &mut *output,
+                    input.name,
+                    &input.records,
+                    &mut input.data,
+                    size,
+                )?;
+            }
+
+            output.write_all(&tar_end())?;
+        }
+    }
+
+    output.flush()
+}
+
+/// a stream whose first block was read ahead
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// reads the first block of `input`, to tell its container, and puts it back
+fn peek<R: Read>(mut input: R) -> Result<(Container, Peeked<R>), std::io::Error> {
+    let mut head = Vec::new();
+    (&mut input).take(BLOCK_LEN).read_to_end(&mut head)?;
+
+    match Container::detect(&head) {
+        Some(c) => Result::Ok((c, Cursor::new(head).chain(input))),
+        None => Result::Err(invalid("Invalid magic number")),
+    }
+}
+
+/// calls `visit` with the name and the data of every member of the archive read
+/// from `input`, in order, until it returns false
+pub fn read_members(
+    input: impl Read,
+    mut visit: impl FnMut(&str, &mut dyn Read) -> Result<bool, std::io::Error>,
+) -> Result<(), std::io::Error> {
+    let (container, mut input) = peek(input)?;
+
+    if container == Container::Tar {
+        for entry in tar::Archive::new(input).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+
+            if !visit(&name, &mut entry)? {
+                break;
+            }
+        }
+
+        return Result::Ok(());
+    }
+
+    loop {
+        let mut reader = cpio::NewcReader::new(input)?;
+
+        if reader.entry().is_trailer() {
+            return Result::Ok(());
+        }
+
+        let name = reader.entry().name().to_string();
+
+        if !visit(&name, &mut reader)? {
+            return Result::Ok(());
+        }
+
+        input = reader.finish()?;
+    }
+}
+
+/// adds a member right before the trailer of an archive
+pub fn append(
+    fd: &mut File,
+    name: &str,
+    data: &mut impl Read,
+    size: u64,
+) -> Result<(), std::io::Error> {
+    let container = Container::of(fd)?;
+
+    if container == Container::Cpio && size > NEWC_MAX_SIZE {
+        return Result::Err(too_large(name));
+    }
+
+    let members = members(fd)?;
+    let trailer = members.last().expect("archives always end with a trailer");
+
+    fd.set_len(trailer.header_offset)?;
+    fd.seek(SeekFrom::Start(trailer.header_offset))?;
+
+    if container == Container::Tar {
+        write_tar_member(&mut *fd, name, &[], data, size)?;
+        fd.write_all(&tar_end())?;
+        fd.flush()?;
+
+        return fd.sync_all();
+    }
+
+    let mut writer = NewcBuilder::new(name)
+        .ino(members.len() as u32 - 1)
+        .uid(1000)
+        .mode(0o100644)
+        .write(&mut *fd, size as u32);
+
+    std::io::copy(data, &mut writer)?;
+    writer.finish()?;
+    cpio::newc::trailer(&mut *fd)?;
+
+    fd.flush()?;
+    fd.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a record holding a value of `len` bytes, and the length it tells
+    fn record(len: usize) -> (Vec<u8>, usize) {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "k", &"v".repeat(len));
+
+        let text = std::str::from_utf8(&pax).unwrap();
+        let told = text.split_once(' ').unwrap().0.parse().unwrap();
+
+        (pax, told)
+    }
+
+    /// a tar archive holding a single member named `name`, with `data`
+    fn archive(name: &str, data: &[u8]) -> Vec<u8> {
+        let size = data.len() as u64;
+        let mut tar = tar_header(name, size, &[("user.ncsum.hash", String::from("ab"))]).unwrap();
+
+        tar.extend(data);
+        tar.extend(tar_padding(size));
+        tar.extend(tar_end());
+
+        tar
+    }
+
+    #[test]
+    fn pax_records_count_their_own_digits() {
+        // "9 k=vvvv\n" is 9 bytes long, with one more byte the length takes a second
+        // digit, so no record is 10, 100 or 1000 bytes long
+        for (value, len) in [
+            (4, 9),
+            (5, 11),
+            (93, 99),
+            (94, 101),
+            (992, 999),
+            (993, 1001),
+        ] {
+            let (pax, told) = record(value);
+
+            assert_eq!((pax.len(), told), (len, len), "value of {value} bytes");
+        }
+
+        for value in 0..1100 {
+            let (pax, told) = record(value);
+
+            assert_eq!(pax.len(), told, "value of {value} bytes");
+            assert!(pax.ends_with(b"\n"));
+        }
+    }
+
+    #[test]
+    fn pax_records_count_bytes() {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, "path", "é");
+
+        assert_eq!(pax, "11 path=é\n".as_bytes());
+    }
+
+    #[test]
+    fn keeps_long_names() {
+        let name = format!("{}.txt", "a".repeat(250));
+        let mut fd = Cursor::new(archive(&name, b"hello\n"));
+        let members = members(&mut fd).unwrap();
+
+        assert_eq!(members[0].name, name);
+        assert_eq!(members[0].size, 6);
+        assert!(members[1].is_trailer());
+
+        fd.seek(SeekFrom::Start(members[0].data_offset)).unwrap();
+        let mut data = vec![0; 6];
+        fd.read_exact(&mut data).unwrap();
+
+        assert_eq!(data, b"hello\n");
+    }
+
+    #[test]
+    fn keeps_names_that_were_not_utf8() {
+        // names reach the archive converted lossily, like to_string_lossy does
+        let lossy = String::from_utf8_lossy(b"caf\xe9-\xff.txt").to_string();
+        let long = "é".repeat(120);
+
+        for name in [lossy, long] {
+            let members = members(&mut Cursor::new(archive(&name, b""))).unwrap();
+
+            assert_eq!(members[0].name, name);
+        }
+    }
+
+    #[test]
+    fn names_members_after_the_file_alone() {
+        let members = members(&mut Cursor::new(archive("dir/sub/a.txt", b"x"))).unwrap();
+
+        assert_eq!(members[0].name, "a.txt");
+    }
+
+    #[test]
+    fn keeps_pax_records() {
+        let mut tar = tar::Archive::new(Cursor::new(archive("a.txt", b"x")));
+        let mut entry = tar.entries().unwrap().next().unwrap().unwrap();
+        let records: Vec<(String, String)> = entry
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .map(|r| r.unwrap())
+            .map(|r| (r.key().unwrap().to_string(), r.value().unwrap().to_string()))
+            .collect();
+
+        assert!(records.contains(&(String::from("user.ncsum.hash"), String::from("ab"))));
+        assert!(records.contains(&(String::from("size"), format!("{:020}", 1))));
+    }
+}
@@ -0,0 +1,170 @@
+//! looks for the leftovers of interrupted or partially failed operations
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+
+use crate::index::{Index, INDEX_NAME};
+use crate::legacy;
+use crate::{list_files, Algorithm, FileInfo};
+
+/// the hash a file name claims, when it looks like `<hash><suffix>`
+pub fn hash_from_name(file: &Path) -> Option<(String, Algorithm)> {
+    let name = file.file_name()?.to_str()?;
+    let stem = match name.find('.') {
+        Some(dot) => &name[..dot],
+        None => name,
+    };
+
+    if !stem.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some((
+        stem.to_ascii_lowercase(),
+        Algorithm::from_hex_len(stem.len())?,
+    ))
+}
+
+/// reports orphaned payloads, dangling sidecars and index records, leftover
+/// temporaries and files whose name doesn't match their content
+///
+/// returns false if any problem was left unfixed
+pub fn audit(dir: &Path, recursive: bool, fix: bool) -> bool {
+    let files = match list_files(dir, recursive) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut clean = true;
+    let mut referenced = HashSet::new();
+
+    for file in files.iter() {
+        let sfname = file.to_string_lossy();
+
+        let infos = if sfname.ends_with(".ncsum") {
+            match FileInfo::load(file) {
+                Ok(i) => vec![i],
+                Err(e) => {
+                    println!("{:?}: unreadable sidecar: {e}", file);
+                    clean = false;
+                    continue;
+                }
+            }
+        } else if sfname.ends_with(INDEX_NAME) {
+            match Index::load(file) {
+                Ok(i) => i.files,
+                Err(e) => {
+                    println!("{:?}: unreadable index: {e}", file);
+                    clean = false;
+                    continue;
+                }
+            }
+        } else {
+            continue;
+        };
+
+        for info in infos {
+            match std::fs::canonicalize(&info.new_name) {
+                Ok(p) => {
+                    referenced.insert(p);
+                }
+                Err(_) => {
+                    println!(
+                        "{:?}: dangling record, {:?} does not exist",
+                        file, info.new_name
+                    );
+                    clean = false;
+                }
+            };
+        }
+    }
+
+    for file in files.iter() {
+        let sfname = file.to_string_lossy();
+
+        if sfname.ends_with(".tncsum") {
+            if fix {
+                match std::fs::remove_file(file) {
+                    Ok(_) => println!("{:?}: leftover temporary file, removed", file),
+                    Err(e) => {
+                        println!("{:?}: leftover temporary file: {e}", file);
+                        clean = false;
+                    }
+                };
+            } else {
+                println!("{:?}: leftover temporary file", file);
+                clean = false;
+            }
+
+            continue;
+        }
+
+        if crate::is_ncsum_file(&sfname) {
+            continue;
+        }
+
+        let (name_hash, algorithm) = match hash_from_name(file) {
+            Some(h) => h,
+            None => continue,
+        };
+
+        // files named by older versions may carry a legacy md5 digest
+        let hashed = match File::open(file)
+            .and_then(|mut fd| legacy::hash_with(&mut fd, algorithm, algorithm.is_md5()))
+        {
+            Ok(h) => h,
+            Err(e) => {
+                println!("{:?}: {e}", file);
+                clean = false;
+                continue;
+            }
+        };
+
+        if !hashed.matches(&name_hash) {
+            println!(
+                "{:?}: the name does not match the content hash {}",
+                file, hashed.hash
+            );
+            clean = false;
+            continue;
+        }
+
+        let is_referenced = match std::fs::canonicalize(file) {
+            Ok(p) => referenced.contains(&p),
+            Err(_) => false,
+        };
+
+        if is_referenced {
+            continue;
+        }
+
+        if !fix {
+            println!("{:?}: orphaned payload without .ncsum", file);
+            clean = false;
+            continue;
+        }
+
+        // the original name is lost, so the sidecar keeps the current one, and the
+        // hash it is named after
+        let mut info = FileInfo::with_hash(file, name_hash, algorithm);
+        info.new_name = String::from(sfname.as_ref());
+        info.old_name = info.new_name.clone();
+
+        match info.save() {
+            Ok(_) => println!(
+                "{:?}: orphaned payload, created {:?}",
+                file, info.ncsum_name
+            ),
+            Err(e) => {
+                println!("{:?}: orphaned payload: {e}", file);
+                clean = false;
+            }
+        };
+    }
+
+    clean
+}
@@ -0,0 +1,117 @@
+//! per-block digests of a file, stored next to its whole-file hash so a
+//! mismatch can be narrowed down to the byte ranges that changed
+
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Algorithm, Hasher};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Blocks {
+    pub block_size: u64,
+    /// size of the whole file
+    pub size: u64,
+    pub hashes: Vec<String>,
+}
+
+/// parses sizes like `4096`, `64K`, `4M` or `2G`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(n) => s.split_at(n),
+        None => (s, ""),
+    };
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Result::Err(format!("invalid size {s:?}")),
+    };
+
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("invalid size {s:?}")),
+        _ => Result::Err(format!("invalid size {s:?}")),
+    }
+}
+
+/// hashes a stream as a whole and block by block in a single pass
+pub fn hash_blocks(
+    fd: &mut impl Read,
+    algorithm: Algorithm,
+    block_size: u64,
+) -> Result<(String, Blocks), std::io::Error> {
+    let mut file_context = Hasher::new(algorithm);
+    let mut block_context = Hasher::new(algorithm);
+    let mut block_len = 0;
+    let mut buffer = vec![0; 1024 * 1024];
+
+    let mut blocks = Blocks {
+        block_size,
+        size: 0,
+        hashes: Vec::new(),
+    };
+
+    loop {
+        let s = fd.read(&mut buffer)?;
+
+        if s == 0 {
+            break;
+        }
+
+        file_context.consume(&buffer[..s]);
+        blocks.size += s as u64;
+
+        let mut data = &buffer[..s];
+
+        while !data.is_empty() {
+            let len = data.len().min((block_size - block_len) as usize);
+
+            block_context.consume(&data[..len]);
+            block_len += len as u64;
+            data = &data[len..];
+
+            if block_len == block_size {
+                let full = std::mem::replace(&mut block_context, Hasher::new(algorithm));
+
+                blocks.hashes.push(full.finish());
+                block_len = 0;
+            }
+        }
+    }
+
+    if block_len > 0 {
+        blocks.hashes.push(block_context.finish());
+    }
+
+    Result::Ok((file_context.finish(), blocks))
+}
+
+impl Blocks {
+    /// byte ranges, as inclusive `(first, last)` pairs, where `actual` differs from these blocks
+    pub fn diff(&self, actual: &Blocks) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let size = self.size.max(actual.size);
+        let count = self.hashes.len().max(actual.hashes.len());
+
+        for n in 0..count {
+            if self.hashes.get(n) == actual.hashes.get(n) {
+                continue;
+            }
+
+            let first = n as u64 * self.block_size;
+            let last = (first + self.block_size).min(size) - 1;
+
+            match ranges.last_mut() {
+                Some(r) if r.1 + 1 == first => r.1 = last,
+                _ => ranges.push((first, last)),
+            }
+        }
+
+        ranges
+    }
+}
@@ -0,0 +1,660 @@
+//! integrity checks that report a status per file instead of stopping at the first problem
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use ed25519_dalek::VerifyingKey;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::blocks::{hash_blocks, Blocks};
+use crate::crypt::Keys;
+use crate::format;
+use crate::legacy::{self, Hashed};
+use crate::mac;
+use crate::sign::{self, Verdict};
+use crate::volume::{self, Packed};
+use crate::{archive, try_get_hash, FileInfo};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Status {
+    Ok,
+    Mismatch,
+    Missing,
+    Unreadable,
+    CorruptSidecar,
+    BadSignature,
+}
+
+impl Status {
+    pub const ALL: [Status; 6] = [
+        Status::Ok,
+        Status::Mismatch,
+        Status::Missing,
+        Status::Unreadable,
+        Status::CorruptSidecar,
+        Status::BadSignature,
+    ];
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Ok => "OK",
+            Status::Mismatch => "MISMATCH",
+            Status::Missing => "MISSING",
+            Status::Unreadable => "UNREADABLE",
+            Status::CorruptSidecar => "CORRUPT_SIDECAR",
+            Status::BadSignature => "BAD_SIGNATURE",
+        })
+    }
+}
+
+pub struct Report {
+    pub status: Status,
+    /// the description of the file, unless the sidecar itself couldn't be read
+    pub info: Option<FileInfo>,
+    /// the hash the file actually has, when it could be computed
+    pub hash: Option<String>,
+    /// the blocks the file actually has, when they were asked for and the description has them
+    pub blocks: Option<Blocks>,
+    /// blocks verified and blocks described, when only a sample of the blocks was verified
+    pub sampled: Option<(usize, usize)>,
+    /// whether only the encrypted data of an encrypted archive was checked, for lack of keys
+    pub ciphertext_only: bool,
+    pub error: Option<String>,
+}
+
+impl Report {
+    fn new(status: Status, info: Option<FileInfo>, error: Option<String>) -> Self {
+        Self {
+            status,
+            info,
+            hash: None,
+            blocks: None,
+            sampled: None,
+            ciphertext_only: false,
+            error,
+        }
+    }
+
+    /// compares the actual hash, and blocks if any, with the description
+    fn compared(info: FileInfo, hashed: Hashed, blocks: Option<Blocks>) -> Self {
+        let status = if hashed.matches(&info.hash) {
+            Status::Ok
+        } else {
+            Status::Mismatch
+        };
+        let note = hashed
+            .is_legacy(&info.hash)
+            .then(|| String::from("described with an older digest, see migrate"));
+
+        let mut report = Self::new(status, Some(info), note);
+        report.hash = Some(hashed.hash);
+        report.blocks = blocks;
+
+        report
+    }
+
+    /// the hash the file was expected to have
+    pub fn expected(&self) -> Option<&str> {
+        self.info.as_ref().map(|i| i.hash.as_str())
+    }
+
+    /// why the file failed its check, as recorded when it is quarantined
+    pub fn reason(&self) -> String {
+        let detail = match (self.status, self.expected(), &self.hash, &self.error) {
+            (Status::Mismatch, Some(e), Some(h), _) => Some(format!("expected {e}, got {h}")),
+            (Status::Mismatch, Some(e), None, error) => {
+                let ranges = self.ranges();
+                let differ = match (error, ranges.first()) {
+                    (Some(error), _) => error.clone(),
+                    (None, Some((first, last))) if ranges.len() == 1 => {
+                        format!("bytes {first}-{last} differ")
+                    }
+                    (None, Some(_)) => format!("{} byte ranges differ", ranges.len()),
+                    (None, None) => String::from("the file differs"),
+                };
+
+                Some(format!("expected {e}, {differ}"))
+            }
+            (_, _, _, error) => error.clone(),
+        };
+
+        match detail {
+            Some(d) => format!("{}: {d}", self.status),
+            None => self.status.to_string(),
+        }
+    }
+
+    /// byte ranges, as inclusive `(first, last)` pairs, that differ from the description
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        match (
+            self.info.as_ref().and_then(|i| i.blocks.as_ref()),
+            &self.blocks,
+        ) {
+            (Some(expected), Some(actual)) => expected.diff(actual),
+            _ => Vec::new(),
+        }
+    }
+
+    fn from_io_error(info: Option<FileInfo>, e: std::io::Error) -> Self {
+        let status = if e.kind() == ErrorKind::NotFound {
+            Status::Missing
+        } else {
+            Status::Unreadable
+        };
+
+        Self::new(status, info, Some(e.to_string()))
+    }
+
+    /// name to show for the checked file
+    pub fn name(&self, sidecar: &Path) -> String {
+        match &self.info {
+            Some(i) => i.old_name.clone(),
+            None => sidecar.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// hashes a file, block by block too when `detail` is set and the description has blocks
+fn hash_file(
+    fd: &mut impl Read,
+    info: Option<&FileInfo>,
+    detail: bool,
+) -> Result<(Hashed, Option<Blocks>), std::io::Error> {
+    let (algorithm, may_be_legacy) = match info {
+        Some(i) => (i.algorithm, i.may_be_legacy()),
+        None => (Default::default(), false),
+    };
+
+    match info.and_then(|i| i.blocks.as_ref()) {
+        Some(b) if detail => {
+            let (hash, blocks) = hash_blocks(fd, algorithm, b.block_size)?;
+
+            Result::Ok((Hashed::from(hash), Some(blocks)))
+        }
+        _ => Result::Ok((legacy::hash_with(fd, algorithm, may_be_legacy)?, None)),
+    }
+}
+
+/// checks the file described by `info` against its hash
+pub fn verify(info: FileInfo, detail: bool) -> Report {
+    let mut fd = match File::open(&info.new_name) {
+        Ok(fd) => fd,
+        Err(e) => return Report::from_io_error(Some(info), e),
+    };
+
+    match hash_file(&mut fd, Some(&info), detail) {
+        Ok((hash, blocks)) => Report::compared(info, hash, blocks),
+        Err(e) => Report::new(Status::Unreadable, Some(info), Some(e.to_string())),
+    }
+}
+
+/// the payload member of an archive, opened and positioned at its start
+fn open_payload(file: &Path) -> Result<(Packed, u64, u64), std::io::Error> {
+    let mut fd = volume::open(file)?;
+    let members = archive::members(&mut fd)?;
+
+    match members.into_iter().find(|m| m.is_payload()) {
+        Some(m) => {
+            fd.seek(SeekFrom::Start(m.data_offset))?;
+
+            Result::Ok((fd, m.data_offset, m.size))
+        }
+        None => Result::Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            "the archive does not contain the file",
+        )),
+    }
+}
+
+/// checks the encrypted data of an archive against its hash, and then, given keys,
+/// the decrypted file against the hash of the description packed along with it
+fn verify_encrypted(file: &Path, info: FileInfo, detail: bool, keys: &Keys) -> Report {
+    let (fd, _, size) = match open_payload(file) {
+        Ok(o) => o,
+        Err(e) => return Report::from_io_error(Some(info), e),
+    };
+
+    let hash = match try_get_hash(&mut fd.take(size), info.algorithm) {
+        Ok(h) => h,
+        Err(e) => return Report::new(Status::Unreadable, Some(info), Some(e.to_string())),
+    };
+
+    if keys.is_empty() || hash != info.hash {
+        let status = match hash == info.hash {
+            true => Status::Ok,
+            false => Status::Mismatch,
+        };
+
+        let mut report = Report::new(status, Some(info), None);
+        report.hash = Some(hash);
+        report.ciphertext_only = true;
+
+        return report;
+    }
+
+    // the encrypted data is an archive of the file and its original description
+    let mut inner: Option<FileInfo> = None;
+    let mut hashed = None;
+
+    let read = open_payload(file).and_then(|(fd, _, size)| {
+        archive::read_members(keys.decrypt(fd.take(size))?, |name, mut data| {
+            if name.ends_with(".ncsum") {
+                inner = Some(format::read(data)?);
+            } else if archive::is_payload_name(name) {
+                hashed = Some(hash_file(&mut data, inner.as_ref(), detail)?);
+            }
+
+            Result::Ok(hashed.is_none())
+        })
+    });
+
+    match (read, inner, hashed) {
+        (Err(e), _, _) => Report::new(Status::Unreadable, Some(info), Some(e.to_string())),
+        (Ok(_), Some(i), Some((hash, blocks))) => Report::compared(i, hash, blocks),
+        (Ok(_), _, _) => Report::new(
+            Status::CorruptSidecar,
+            Some(info),
+            Some(String::from(
+                "the encrypted data does not hold a described file",
+            )),
+        ),
+    }
+}
+
+/// checks the file described by a .ncsum file, or packed in a .pncsum archive,
+/// split into volumes or not, decrypting it with `keys` when it is encrypted
+pub fn verify_file(file: &Path, detail: bool, keys: &Keys) -> Report {
+    if !file.to_string_lossy().ends_with(".pncsum") && !volume::is_manifest(file) {
+        let info = match File::open(file) {
+            Ok(mut fd) => match format::read(&mut fd) {
+                Ok(i) => i,
+                Err(e) => return Report::new(Status::CorruptSidecar, None, Some(e.to_string())),
+            },
+            Err(e) => return Report::from_io_error(None, e),
+        };
+
+        return verify(info, detail);
+    }
+
+    let mut fd = match volume::open(file) {
+        Ok(fd) => fd,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            return Report::new(Status::CorruptSidecar, None, Some(e.to_string()))
+        }
+        Err(e) => return Report::from_io_error(None, e),
+    };
+
+    let mut info: Option<FileInfo> = None;
+    let mut hash: Option<(Hashed, Option<Blocks>)> = None;
+    let mut failed: Option<Report> = None;
+
+    let read = archive::read_members(&mut fd, |name, mut data| {
+        if name.ends_with(".ncsum") {
+            match format::read(data) {
+                Ok(i) => info = Some(i),
+                Err(e) => {
+                    failed = Some(Report::new(
+                        Status::CorruptSidecar,
+                        None,
+                        Some(e.to_string()),
+                    ));
+                }
+            };
+        } else if archive::is_payload_name(name) {
+            if let Some(i) = info.take_if(|i| i.is_encrypted()) {
+                failed = Some(verify_encrypted(file, i, detail, keys));
+            } else {
+                match hash_file(&mut data, info.as_ref(), detail) {
+                    Ok(h) => hash = Some(h),
+                    Err(e) => {
+                        let i = info.take();
+                        failed = Some(Report::new(Status::Unreadable, i, Some(e.to_string())));
+                    }
+                };
+            }
+        }
+
+        Result::Ok(failed.is_none())
+    });
+
+    // a missing or corrupt volume is told by name, whatever else went wrong
+    if let Err(e) = fd.finish() {
+        let info = info.or(failed.and_then(|r| r.info));
+        let status = match (e.kind(), &info) {
+            (ErrorKind::NotFound, _) => Status::Missing,
+            (ErrorKind::InvalidData, Some(_)) => Status::Mismatch,
+            (ErrorKind::InvalidData, None) => Status::CorruptSidecar,
+            _ => Status::Unreadable,
+        };
+
+        return Report::new(status, info, Some(e.to_string()));
+    }
+
+    if let Some(report) = failed {
+        return report;
+    }
+
+    if let Err(e) = read {
+        // past the .ncsum entry, what is left to read is the file
+        let status = match info {
+            Some(_) => Status::Unreadable,
+            None => Status::CorruptSidecar,
+        };
+
+        return Report::new(status, info, Some(e.to_string()));
+    }
+
+    match (info, hash) {
+        (None, _) => Report::new(
+            Status::CorruptSidecar,
+            None,
+            Some(String::from("the archive does not contain a .ncsum file")),
+        ),
+        (Some(i), None) => Report::new(
+            Status::Missing,
+            Some(i),
+            Some(String::from("the archive does not contain the file")),
+        ),
+        (Some(i), Some((h, blocks))) => Report::compared(i, h, blocks),
+    }
+}
+
+/// rejects a .ncsum file or .pncsum archive that isn't signed by one of the
+/// `trusted` keys, before anything it describes is trusted
+pub fn verify_signature(file: &Path, trusted: &[VerifyingKey]) -> Option<Report> {
+    match sign::verify_file(file, trusted) {
+        Ok(Verdict::Valid) => None,
+        Ok(v) => Some(Report::new(
+            Status::BadSignature,
+            FileInfo::load(&file.to_path_buf()).ok(),
+            Some(match v {
+                Verdict::Untrusted(key) => format!("signed by an untrusted key, {key}"),
+                Verdict::Unsigned => String::from("not signed"),
+                _ => String::from("the signature doesn't match"),
+            }),
+        )),
+        Err(e) if e.kind() == ErrorKind::InvalidData => Some(Report::new(
+            Status::CorruptSidecar,
+            None,
+            Some(e.to_string()),
+        )),
+        Err(e) => Some(Report::from_io_error(None, e)),
+    }
+}
+
+/// rejects a description without an HMAC matching `key`, before its hash is trusted,
+/// `info` being the description of an index record or `None` to read it from `file`
+pub fn verify_hmac(file: &Path, info: Option<&FileInfo>, key: &[u8]) -> Option<Report> {
+    let info = match info {
+        Some(i) => i.clone(),
+        None => match FileInfo::load(&file.to_path_buf()) {
+            Ok(i) => i,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                return Some(Report::new(
+                    Status::CorruptSidecar,
+                    None,
+                    Some(e.to_string()),
+                ))
+            }
+            Err(e) => return Some(Report::from_io_error(None, e)),
+        },
+    };
+
+    match mac::verify(&info, key) {
+        Ok(None) => None,
+        Ok(Some(why)) => Some(Report::new(
+            Status::BadSignature,
+            Some(info),
+            Some(why.to_string()),
+        )),
+        Err(e) => Some(Report::new(
+            Status::CorruptSidecar,
+            Some(info),
+            Some(e.to_string()),
+        )),
+    }
+}
+
+/// parses percentages like `5%` or `5`, as a fraction
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Result::Ok(p / 100.0),
+        _ => Result::Err(format!(
+            "invalid percentage {s:?}, it must be between 0% and 100%"
+        )),
+    }
+}
+
+/// parses durations like `90s`, `30m`, `2h` or `7d`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) =
+        s.split_at(s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len());
+
+    let seconds: u64 = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Result::Err(format!("invalid duration {s:?}")),
+    };
+
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(seconds))
+    {
+        Some(n) => Result::Ok(Duration::from_secs(n)),
+        None => Result::Err(format!("invalid duration {s:?}")),
+    }
+}
+
+/// checks a random `fraction` of the blocks of the file described by `info`, or by
+/// the .ncsum or .pncsum `file` when there is no `info`
+///
+/// files described without block hashes, and encrypted files, are fully checked,
+/// but only with a `fraction` chance, `None` means the file was left out of the sample
+pub fn verify_sample(
+    file: &Path,
+    info: Option<FileInfo>,
+    fraction: f64,
+    keys: &Keys,
+) -> Option<Report> {
+    let mut rng = rand::thread_rng();
+    let packed = info.is_none()
+        && (file.to_string_lossy().ends_with(".pncsum") || volume::is_manifest(file));
+
+    let info = match info {
+        Some(i) => i,
+        None => match FileInfo::load(&file.to_path_buf()) {
+            Ok(i) => i,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                return Some(Report::new(
+                    Status::CorruptSidecar,
+                    None,
+                    Some(e.to_string()),
+                ))
+            }
+            Err(e) => return Some(Report::from_io_error(None, e)),
+        },
+    };
+
+    let expected = match &info.blocks {
+        Some(b) if !info.is_encrypted() => b.clone(),
+        _ if rng.gen_bool(fraction) => {
+            return Some(match packed {
+                true => verify_file(file, false, keys),
+                false => verify(info, false),
+            })
+        }
+        _ => return None,
+    };
+
+    // where the file lies, inside its archive for packed files
+    let opened = if packed {
+        open_payload(file)
+    } else {
+        File::open(&info.new_name).and_then(|fd| {
+            let size = fd.metadata()?.len();
+
+            Result::Ok((Packed::Single(fd), 0, size))
+        })
+    };
+
+    let (mut fd, offset, size) = match opened {
+        Ok(o) => o,
+        Err(e) => return Some(Report::from_io_error(Some(info), e)),
+    };
+
+    let total = expected.hashes.len();
+    let amount = ((total as f64 * fraction).ceil() as usize).clamp(1.min(total), total);
+    let mut actual = expected.clone();
+    actual.size = size;
+
+    for n in rand::seq::index::sample(&mut rng, total, amount) {
+        let start = n as u64 * expected.block_size;
+        let len = expected.block_size.min(size.saturating_sub(start));
+
+        let hashed = fd
+            .seek(SeekFrom::Start(offset + start))
+            .and_then(|_| try_get_hash(&mut (&mut fd).take(len), info.algorithm));
+
+        match hashed {
+            Ok(h) => actual.hashes[n] = h,
+            Err(e) => {
+                return Some(Report::new(
+                    Status::Unreadable,
+                    Some(info),
+                    Some(e.to_string()),
+                ))
+            }
+        }
+    }
+
+    let status = if actual == expected {
+        Status::Ok
+    } else {
+        Status::Mismatch
+    };
+
+    let mut report = Report::new(status, Some(info), None);
+    report.blocks = Some(actual);
+    report.sampled = Some((amount, total));
+
+    Some(report)
+}
+
+/// the last file a check with a time budget got to
+pub fn load_cursor(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// sorts `targets` by `key`, starting after the `last` one a check got to, and
+/// going round to the ones before it
+pub fn from_cursor<T>(targets: &mut [T], key: impl Fn(&T) -> String, last: Option<&str>) {
+    targets.sort_by_key(&key);
+
+    if let Some(last) = last {
+        let start = targets.partition_point(|t| key(t).as_str() <= last);
+        targets.rotate_left(start);
+    }
+}
+
+/// remembers the last checked file, or forgets it once every file has been checked
+pub fn save_cursor(path: &Path, last: Option<&str>) -> Result<(), std::io::Error> {
+    match last {
+        Some(l) => std::fs::write(path, l),
+        None => match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Result::Err(e),
+            _ => Result::Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_tell_what_failed() {
+        let info = FileInfo::with_hash(&"f".into(), "abc".to_string(), Default::default());
+
+        let report = Report::compared(info.clone(), Hashed::from("def".to_string()), None);
+        assert_eq!(report.reason(), "MISMATCH: expected abc, got def");
+
+        let e = std::io::Error::new(ErrorKind::NotFound, "no such file");
+        let report = Report::from_io_error(Some(info.clone()), e);
+        assert_eq!(report.reason(), "MISSING: no such file");
+
+        let report = Report::new(
+            Status::Mismatch,
+            Some(info),
+            Some("volume is corrupt".into()),
+        );
+        assert_eq!(report.reason(), "MISMATCH: expected abc, volume is corrupt");
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(7 * 86400)));
+
+        for s in ["", "h", "2x", "-1s", "1.5h", "40000000000000w"] {
+            assert!(parse_duration(s).is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn parses_fractions() {
+        assert_eq!(parse_fraction("5%"), Ok(0.05));
+        assert_eq!(parse_fraction("100"), Ok(1.0));
+
+        for s in ["0%", "101%", "-5", "five"] {
+            assert!(parse_fraction(s).is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn budget_checks_start_after_the_cursor() {
+        let key = |s: &&str| s.to_string();
+        let mut targets = ["c", "a", "d", "b"];
+
+        from_cursor(&mut targets, key, None);
+        assert_eq!(targets, ["a", "b", "c", "d"]);
+
+        from_cursor(&mut targets, key, Some("b"));
+        assert_eq!(targets, ["c", "d", "a", "b"]);
+
+        // a file that is gone still tells where to go on
+        from_cursor(&mut targets, key, Some("bb"));
+        assert_eq!(targets, ["c", "d", "a", "b"]);
+
+        from_cursor(&mut targets, key, Some("d"));
+        assert_eq!(targets, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn cursor_is_forgotten_after_a_full_pass() {
+        let path = std::env::temp_dir().join(format!("ncsum-cursor-{}", std::process::id()));
+
+        assert_eq!(load_cursor(&path), None);
+
+        save_cursor(&path, Some("b")).unwrap();
+        assert_eq!(load_cursor(&path).as_deref(), Some("b"));
+
+        save_cursor(&path, None).unwrap();
+        assert_eq!(load_cursor(&path), None);
+        save_cursor(&path, None).unwrap();
+    }
+}
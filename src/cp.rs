@@ -0,0 +1,364 @@
+//! copies that are hashed while they are written and read back before they take
+//! their final name, so a copied file never needs a separate check
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audit::hash_from_name;
+use crate::format::Format;
+use crate::legacy::{self, Hashed, Verifier};
+use crate::sign::signature_name;
+use crate::volume::{self, Packed, Volumes};
+use crate::{archive, try_get_hash, Algorithm, FileInfo};
+
+fn temporary(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tncsum", path.to_string_lossy()))
+}
+
+fn mismatch(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// copies `from` to `to`, flushed to disk, returning the hash of what was read,
+/// and its `legacy` digest when it is asked for
+fn copy_hashed(
+    from: &Path,
+    to: &Path,
+    algorithm: Algorithm,
+    legacy: bool,
+) -> Result<Hashed, std::io::Error> {
+    let mut input = File::open(from)?;
+    let mut output = File::create(to)?;
+    let mut context = Verifier::with(algorithm, legacy);
+    let mut buffer = vec![0; 1024 * 1024];
+
+    loop {
+        let s = input.read(&mut buffer)?;
+
+        if s == 0 {
+            break;
+        }
+
+        context.consume(&buffer[..s]);
+        output.write_all(&buffer[..s])?;
+    }
+
+    output.sync_all()?;
+
+    Result::Ok(context.finish())
+}
+
+/// the hash of the payload of an archive described by `info`, the volumes of a split
+/// archive being checked against their hash too
+pub fn packed_hash(mut fd: Packed, info: &FileInfo) -> Result<Hashed, std::io::Error> {
+    let member = match archive::members(&mut fd)?
+        .into_iter()
+        .find(|m| m.is_payload())
+    {
+        Some(m) => m,
+        None => {
+            return Result::Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the archive does not contain the file",
+            ))
+        }
+    };
+
+    fd.seek(SeekFrom::Start(member.data_offset))?;
+
+    let hashed = legacy::hash(&mut (&mut fd).take(member.size), info)?;
+    fd.finish()?;
+
+    Result::Ok(hashed)
+}
+
+/// gives a copy its final name once `written` is known to be right, or drops it
+fn finish(
+    tname: &Path,
+    to: &Path,
+    written: Result<bool, std::io::Error>,
+) -> Result<(), std::io::Error> {
+    match written {
+        Ok(true) => std::fs::rename(tname, to),
+        Ok(false) => {
+            let _ = std::fs::remove_file(tname);
+
+            Result::Err(mismatch("the copy does not match its hash"))
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(tname);
+
+            Result::Err(e)
+        }
+    }
+}
+
+/// copies `from` to `to` and returns its hash, failing when the source doesn't
+/// match its description `described` or when what was written doesn't read back the same
+pub fn copy_verified(
+    from: &Path,
+    to: &Path,
+    algorithm: Algorithm,
+    described: Option<&FileInfo>,
+) -> Result<String, std::io::Error> {
+    let tname = temporary(to);
+    let legacy = described.is_some_and(|i| i.may_be_legacy());
+    let read = copy_hashed(from, &tname, algorithm, legacy)?;
+
+    if described.is_some_and(|i| !read.matches(&i.hash)) {
+        let _ = std::fs::remove_file(&tname);
+
+        return Result::Err(mismatch("the source file does not match its hash"));
+    }
+
+    let written = File::open(&tname)
+        .and_then(|mut fd| try_get_hash(&mut fd, algorithm))
+        .map(|h| h == read.hash);
+    finish(&tname, to, written)?;
+
+    Result::Ok(read.hash)
+}
+
+/// copies a .pncsum archive, checking the file packed in the copy against its hash
+pub fn copy_packed(from: &Path, to: &Path, info: &FileInfo) -> Result<(), std::io::Error> {
+    let tname = temporary(to);
+    copy_hashed(from, &tname, info.algorithm, false)?;
+
+    let written = File::open(&tname)
+        .and_then(|fd| packed_hash(Packed::Single(fd), info))
+        .map(|h| h.matches(&info.hash));
+    finish(&tname, to, written)
+}
+
+/// copies an archive split into volumes next to `to`, and its .vncsum manifest to
+/// `to`, checking the volumes and the file packed in the copy against their hash
+pub fn copy_split(from: &Path, to: &Path, info: &FileInfo) -> Result<(), std::io::Error> {
+    let dir = to.parent().unwrap_or(Path::new(""));
+    let mut files = volume::files(from)?;
+    let mut copied = Vec::new();
+
+    // the manifest goes last, so there is never a manifest without its volumes
+    let manifest = files.pop().expect("the manifest is listed last");
+    let tname = temporary(to);
+
+    let written = files
+        .iter()
+        .try_for_each(|f| {
+            let volume_to = dir.join(f.file_name().expect("Error getting file name"));
+
+            copy_verified(f, &volume_to, info.algorithm, None)?;
+            copied.push(volume_to);
+
+            Result::Ok(())
+        })
+        .and_then(|_| copy_hashed(&manifest, &tname, info.algorithm, false))
+        .and_then(|_| packed_hash(Packed::Split(Volumes::open(&tname)?), info))
+        .map(|h| h.matches(&info.hash));
+
+    if !matches!(written, Ok(true)) {
+        for volume in copied {
+            let _ = std::fs::remove_file(volume);
+        }
+    }
+
+    finish(&tname, to, written)
+}
+
+/// writes a .ncsum file through a temporary file, replacing any previous one
+pub fn write_sidecar(info: &FileInfo, format: Format) -> Result<(), std::io::Error> {
+    let to = PathBuf::from(&info.ncsum_name);
+    let tname = temporary(&to);
+
+    let mut fd = File::create(&tname)?;
+    fd.write_all(&format.encode(info)?)?;
+    fd.sync_all()?;
+    std::fs::rename(&tname, &to)
+}
+
+/// the file a .ncsum file describes, looked for next to it when it isn't where it was named
+pub fn payload(sidecar: &Path, info: &FileInfo) -> PathBuf {
+    let recorded = PathBuf::from(&info.new_name);
+
+    match (recorded.exists(), sidecar.parent(), recorded.file_name()) {
+        (false, Some(dir), Some(name)) => dir.join(name),
+        _ => recorded,
+    }
+}
+
+/// recovery data stored next to a .ncsum file
+pub fn recovery(sidecar: &Path) -> PathBuf {
+    PathBuf::from(sidecar.to_string_lossy().replace(".ncsum", ".rncsum"))
+}
+
+/// copies the file described by `sidecar` to `to`, along with its recovery data and
+/// signature, and describes the copy with a .ncsum file at `sidecar_to`
+fn copy_described(
+    sidecar: &Path,
+    info: &FileInfo,
+    to: &Path,
+    sidecar_to: &Path,
+) -> Result<FileInfo, std::io::Error> {
+    // the file goes first, so there is never a .ncsum file without its file
+    copy_verified(&payload(sidecar, info), to, info.algorithm, Some(info))?;
+
+    // the signature covers file names only, so it holds for the copy too
+    for (from, to) in [
+        (recovery(sidecar), recovery(sidecar_to)),
+        (signature_name(sidecar), signature_name(sidecar_to)),
+    ] {
+        if from.exists() {
+            std::fs::copy(&from, temporary(&to))?;
+            std::fs::rename(temporary(&to), to)?;
+        }
+    }
+
+    let dir = to.parent().unwrap_or(Path::new(""));
+    let mut info = info.clone();
+
+    info.old_name = match Path::new(&info.old_name).file_name() {
+        Some(n) => dir.join(n).to_string_lossy().to_string(),
+        None => info.old_name,
+    };
+    info.new_name = to.to_string_lossy().to_string();
+    info.ncsum_name = sidecar_to.to_string_lossy().to_string();
+
+    // the copy is written in the encoding of the original
+    write_sidecar(&info, Format::detect(&std::fs::read(sidecar)?))?;
+
+    Result::Ok(info)
+}
+
+/// copies a .ncsum file along with its file, a .pncsum archive, or a split archive,
+/// into `dir`, returning where the .ncsum file, the archive or its manifest was copied
+pub fn copy_into(file: &Path, info: &FileInfo, dir: &Path) -> Result<PathBuf, std::io::Error> {
+    std::fs::create_dir_all(dir)?;
+
+    let to = dir.join(file.file_name().expect("Error getting file name"));
+
+    if file.to_string_lossy().ends_with(".pncsum") {
+        copy_packed(file, &to, info)?;
+    } else if volume::is_manifest(file) {
+        copy_split(file, &to, info)?;
+    } else {
+        let payload = payload(file, info);
+        let payload_to = dir.join(payload.file_name().expect("Error getting file name"));
+
+        copy_described(file, info, &payload_to, &to)?;
+    }
+
+    Result::Ok(to)
+}
+
+/// copies `src` to `dst`, or into it if it is a directory, and returns where it went
+///
+/// a .ncsum file brings its file along, and so does a file named after its hash
+/// with its .ncsum file next to it, while any other file gets a .ncsum file
+/// describing the copy
+pub fn cp(src: &Path, dst: &Path, algorithm: Algorithm) -> Result<PathBuf, std::io::Error> {
+    let sfname = src.to_string_lossy();
+
+    // a named file is copied with the .ncsum file describing it
+    let described =
+        if sfname.ends_with(".ncsum") || sfname.ends_with(".pncsum") || volume::is_manifest(src) {
+            Some(src.to_path_buf())
+        } else {
+            hash_from_name(src)
+                .map(|(hash, _)| src.with_file_name(hash + ".ncsum"))
+                .filter(|s| s.exists())
+        };
+
+    if let Some(sidecar) = described {
+        let info = FileInfo::load(&sidecar)?;
+
+        if dst.is_dir() {
+            return copy_into(&sidecar, &info, dst);
+        }
+
+        if sidecar.to_string_lossy().ends_with(".pncsum") {
+            copy_packed(&sidecar, dst, &info)?;
+
+            return Result::Ok(dst.to_path_buf());
+        }
+
+        if volume::is_manifest(&sidecar) {
+            copy_split(&sidecar, dst, &info)?;
+
+            return Result::Ok(dst.to_path_buf());
+        }
+
+        let sidecar_to = dst.with_file_name(sidecar.file_name().expect("Error getting file name"));
+
+        return copy_described(&sidecar, &info, dst, &sidecar_to).map(|_| dst.to_path_buf());
+    }
+
+    let to = match dst.is_dir() {
+        true => dst.join(src.file_name().expect("Error getting file name")),
+        false => dst.to_path_buf(),
+    };
+
+    let hash = copy_verified(src, &to, algorithm, None)?;
+    let name = to.to_string_lossy().to_string();
+
+    write_sidecar(
+        &FileInfo {
+            ncsum_name: to
+                .with_file_name(hash.clone() + ".ncsum")
+                .to_string_lossy()
+                .to_string(),
+            hash,
+            algorithm,
+            old_name: name.clone(),
+            new_name: name,
+            blocks: None,
+            encryption: None,
+            hmac: None,
+        },
+        Format::default(),
+    )?;
+
+    Result::Ok(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::check::{self, Status};
+    use crate::crypt::Keys;
+    use crate::sign::{self, Verdict};
+
+    #[test]
+    fn copies_stay_checked_and_signed() {
+        let dir = std::env::temp_dir().join(format!("ncsum-cp-{}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("copy")).unwrap();
+
+        let file = dir.join("file.txt");
+        std::fs::write(&file, b"copied with its signature\n").unwrap();
+
+        let info = FileInfo::try_with_blocks(&file, None).unwrap();
+        std::fs::rename(&file, &info.new_name).unwrap();
+        info.save().unwrap();
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sidecar = PathBuf::from(&info.ncsum_name);
+        sign::sign_file(&sidecar, &key).unwrap();
+
+        let copy = cp(&sidecar, &dir.join("copy"), Algorithm::Md5).unwrap();
+
+        assert_eq!(
+            check::verify_file(&copy, false, &Keys::load(&[], None).unwrap()).status,
+            Status::Ok
+        );
+        assert_eq!(
+            sign::verify_file(&copy, &[key.verifying_key()]).unwrap(),
+            Verdict::Valid
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! age encryption of the file packed in a .pncsum archive, to a passphrase or to
+//! X25519 recipients
+//!
+//! the file is packed along with its .ncsum file into an inner archive, which is
+//! what gets encrypted, so its name and hash can only be read once decrypted. The
+//! .ncsum entry of the encrypted archive only describes the encrypted data, which
+//! anyone can check, and the archive is named after its hash
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use age::secrecy::SecretString;
+use age::{x25519, Decryptor, Encryptor, Identity, IdentityFile};
+use serde::{Deserialize, Serialize};
+
+use crate::{try_get_hash, Algorithm};
+
+/// environment variable read for the passphrase when no passphrase file is given
+pub const PASSPHRASE_VAR: &str = "NCSUM_PASSPHRASE";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    Passphrase,
+    X25519,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Encryption {
+    pub method: Method,
+}
+
+fn invalid(e: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// parses an X25519 recipient, like `age1…`
+pub fn parse_recipient(s: &str) -> Result<x25519::Recipient, String> {
+    x25519::Recipient::from_str(s).map_err(|e| format!("invalid recipient {s:?}: {e}"))
+}
+
+/// reads the passphrase from the first line of `file`, or from `NCSUM_PASSPHRASE`
+fn load_passphrase(file: Option<&Path>) -> Result<Option<SecretString>, std::io::Error> {
+    let passphrase = match file {
+        Some(f) => std::fs::read_to_string(f)?
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        None => match std::env::var(PASSPHRASE_VAR) {
+            Ok(p) => p,
+            Err(_) => return Result::Ok(None),
+        },
+    };
+
+    match passphrase.is_empty() {
+        true => Result::Err(invalid("the passphrase is empty")),
+        false => Result::Ok(Some(SecretString::from(passphrase))),
+    }
+}
+
+/// who an archive is encrypted for
+pub struct Recipients {
+    recipients: Vec<x25519::Recipient>,
+    passphrase: Option<SecretString>,
+}
+
+impl Recipients {
+    /// encrypts to `recipients`, or to a passphrase when there are none
+    pub fn load(
+        recipients: Vec<x25519::Recipient>,
+        passphrase_file: Option<&Path>,
+    ) -> Result<Self, std::io::Error> {
+        if !recipients.is_empty() {
+            return Result::Ok(Self {
+                recipients,
+                passphrase: None,
+            });
+        }
+
+        match load_passphrase(passphrase_file)? {
+            Some(p) => Result::Ok(Self {
+                recipients,
+                passphrase: Some(p),
+            }),
+            None => Result::Err(invalid(format!(
+                "encrypting needs a recipient, or a passphrase from --passphrase-file or {PASSPHRASE_VAR}"
+            ))),
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        match self.passphrase {
+            Some(_) => Method::Passphrase,
+            None => Method::X25519,
+        }
+    }
+
+    /// encrypts what `write` writes into `to`, returning the hash of the encrypted file
+    pub fn encrypt(
+        &self,
+        to: &Path,
+        algorithm: Algorithm,
+        write: impl FnOnce(&mut dyn Write) -> Result<(), std::io::Error>,
+    ) -> Result<String, std::io::Error> {
+        let encryptor = match &self.passphrase {
+            Some(p) => Encryptor::with_user_passphrase(p.clone()),
+            None => {
+                Encryptor::with_recipients(self.recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(invalid)?
+            }
+        };
+
+        let mut writer = encryptor.wrap_output(File::create(to)?)?;
+        write(&mut writer)?;
+        writer.finish()?.sync_all()?;
+
+        try_get_hash(&mut File::open(to)?, algorithm)
+    }
+}
+
+/// what encrypted archives can be opened with
+pub struct Keys {
+    identities: Vec<Box<dyn Identity>>,
+    passphrase: Option<SecretString>,
+}
+
+impl Keys {
+    /// reads the identity files, and the passphrase from `passphrase_file` or from
+    /// `NCSUM_PASSPHRASE`, none of which is required
+    pub fn load(
+        identity_files: &[PathBuf],
+        passphrase_file: Option<&Path>,
+    ) -> Result<Self, std::io::Error> {
+        let mut identities = Vec::new();
+
+        for file in identity_files {
+            let loaded = IdentityFile::from_file(file.to_string_lossy().to_string())?
+                .into_identities()
+                .map_err(|e| invalid(format!("{e}: {file:?}")))?;
+
+            identities.extend(loaded);
+        }
+
+        Result::Ok(Self {
+            identities,
+            passphrase: load_passphrase(passphrase_file)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.passphrase.is_none()
+    }
+
+    /// decrypts the data read from `input`
+    pub fn decrypt<R: Read>(&self, input: R) -> Result<impl Read, std::io::Error> {
+        if self.is_empty() {
+            return Result::Err(invalid(format!(
+                "the file is encrypted, decrypting it needs --identity, --passphrase-file or {PASSPHRASE_VAR}"
+            )));
+        }
+
+        let decryptor = Decryptor::new(input).map_err(invalid)?;
+
+        let scrypt = self
+            .passphrase
+            .as_ref()
+            .map(|p| age::scrypt::Identity::new(p.clone()));
+
+        let identities = self
+            .identities
+            .iter()
+            .map(|i| i.as_ref())
+            .chain(scrypt.iter().map(|i| i as &dyn Identity));
+
+        decryptor.decrypt(identities).map_err(invalid)
+    }
+}
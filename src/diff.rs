@@ -0,0 +1,196 @@
+//! compares two collections by content, each one being a directory of .ncsum,
+//! .pncsum and .vncsum files or collection indexes, a collection index, or a checksum list
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::index::{Index, INDEX_NAME};
+use crate::manifest::{self, Entry};
+use crate::volume;
+use crate::{list_files, FileInfo};
+
+#[derive(Serialize, Debug)]
+pub struct Changed {
+    pub name: String,
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Moved {
+    pub hash: String,
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Diff {
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+    /// same name, different hash
+    pub changed: Vec<Changed>,
+    /// same hash, different name
+    pub moved: Vec<Moved>,
+    /// same name, but hashed with different algorithms
+    pub incomparable: Vec<String>,
+    pub identical: usize,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty()
+            && self.only_in_b.is_empty()
+            && self.changed.is_empty()
+            && self.moved.is_empty()
+            && self.incomparable.is_empty()
+    }
+}
+
+/// `name` relative to `root`, when it lies inside it
+fn relative(name: &Path, root: &Path) -> String {
+    name.strip_prefix(root)
+        .unwrap_or(name)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// an entry for a file described by a .ncsum, .pncsum or .vncsum file, named after where
+/// the description lies, since the original file was next to it
+fn described(file: &Path, root: &Path, info: FileInfo) -> Entry {
+    let name = match (file.parent(), Path::new(&info.old_name).file_name()) {
+        (Some(parent), Some(n)) => PathBuf::from(relative(parent, root)).join(n),
+        _ => PathBuf::from(&info.old_name),
+    };
+
+    Entry {
+        algorithm: info.algorithm,
+        hash: info.hash,
+        name: name.to_string_lossy().to_string(),
+    }
+}
+
+fn load_file(file: &Path, root: &Path) -> Result<Vec<Entry>, std::io::Error> {
+    let sfname = file.to_string_lossy();
+
+    if sfname.ends_with(".ncsum") || sfname.ends_with(".pncsum") || volume::is_manifest(file) {
+        let info = FileInfo::load(&file.to_path_buf())?;
+
+        return Result::Ok(vec![described(file, root, info)]);
+    }
+
+    if sfname.ends_with(INDEX_NAME) {
+        let index_dir = file.parent().unwrap_or(root);
+
+        return Result::Ok(
+            Index::load(file)?
+                .files
+                .into_iter()
+                .map(|i| Entry {
+                    algorithm: i.algorithm,
+                    name: relative(Path::new(&i.old_name), index_dir),
+                    hash: i.hash,
+                })
+                .collect(),
+        );
+    }
+
+    // anything else is taken for a checksum list
+    let content = std::fs::read_to_string(file)?;
+
+    Result::Ok(
+        content
+            .lines()
+            .filter_map(|l| manifest::parse_line(l, None))
+            .collect(),
+    )
+}
+
+/// every file described inside `path`, with its name relative to `path`
+pub fn load(path: &Path, recursive: bool) -> Result<Vec<Entry>, std::io::Error> {
+    if !path.is_dir() {
+        return load_file(path, path.parent().unwrap_or(Path::new("")));
+    }
+
+    let mut entries = Vec::new();
+
+    for file in list_files(path, recursive)? {
+        let sfname = file.to_string_lossy();
+
+        if sfname.ends_with(".ncsum")
+            || sfname.ends_with(".pncsum")
+            || volume::is_manifest(&file)
+            || sfname.ends_with(INDEX_NAME)
+        {
+            entries.extend(load_file(&file, path)?);
+        }
+    }
+
+    Result::Ok(entries)
+}
+
+/// compares the files of two collections, by name first and then by content
+pub fn diff(a: Vec<Entry>, b: Vec<Entry>) -> Diff {
+    let mut result = Diff::default();
+
+    let a_names: HashMap<&str, &Entry> = a.iter().map(|e| (e.name.as_str(), e)).collect();
+    let b_names: HashMap<&str, &Entry> = b.iter().map(|e| (e.name.as_str(), e)).collect();
+
+    // files only on one side can still be there under another name
+    let mut only_b: HashMap<(&str, &str), Vec<&Entry>> = HashMap::new();
+
+    for eb in b.iter().filter(|e| !a_names.contains_key(e.name.as_str())) {
+        only_b
+            .entry((eb.algorithm.tag(), eb.hash.as_str()))
+            .or_default()
+            .push(eb);
+    }
+
+    for ea in a.iter() {
+        let eb = match b_names.get(ea.name.as_str()) {
+            Some(eb) => eb,
+            None => {
+                match only_b
+                    .get_mut(&(ea.algorithm.tag(), ea.hash.as_str()))
+                    .and_then(Vec::pop)
+                {
+                    Some(eb) => result.moved.push(Moved {
+                        hash: ea.hash.clone(),
+                        a: ea.name.clone(),
+                        b: eb.name.clone(),
+                    }),
+                    None => result.only_in_a.push(ea.name.clone()),
+                }
+
+                continue;
+            }
+        };
+
+        if ea.algorithm != eb.algorithm {
+            result.incomparable.push(ea.name.clone());
+        } else if ea.hash != eb.hash {
+            result.changed.push(Changed {
+                name: ea.name.clone(),
+                a: ea.hash.clone(),
+                b: eb.hash.clone(),
+            });
+        } else {
+            result.identical += 1;
+        }
+    }
+
+    result.only_in_b = only_b
+        .into_values()
+        .flatten()
+        .map(|e| e.name.clone())
+        .collect();
+
+    result.only_in_a.sort();
+    result.only_in_b.sort();
+    result.changed.sort_by(|x, y| x.name.cmp(&y.name));
+    result.moved.sort_by(|x, y| x.a.cmp(&y.a));
+    result.incomparable.sort();
+
+    result
+}
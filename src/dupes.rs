@@ -0,0 +1,334 @@
+//! finds files with the same content, using the hashes ncsum already knows and
+//! hashing the rest, and optionally replaces the copies with links to one of them
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::index::{Index, INDEX_NAME};
+use crate::volume;
+use crate::{archive, is_ncsum_file, list_files, try_get_hash, Algorithm, FileInfo};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    /// replace duplicates with hardlinks to the first copy
+    Hardlink,
+    /// replace duplicates with reflinks to the first copy, on filesystems that support them
+    Reflink,
+}
+
+/// one copy of some content, either a file or the payload of a .pncsum archive
+struct Found {
+    /// the file holding the content, the archive or the manifest of its volumes for packed files
+    path: PathBuf,
+    /// where the content starts inside the archive, for packed files
+    member: Option<u64>,
+    size: u64,
+    /// the original name, for files described by ncsum
+    old_name: Option<String>,
+    /// the md5 hash of the content, once known
+    hash: Option<String>,
+    /// device and inode, so hardlinked copies are not taken for duplicates
+    inode: (u64, u64),
+}
+
+impl Found {
+    fn describe(&self) -> String {
+        let packed = match self.member {
+            Some(_) => "packed, ",
+            None => "",
+        };
+
+        match &self.old_name {
+            Some(n) => format!("{:?} ({packed}was {n:?})", self.path),
+            None => format!("{:?}", self.path),
+        }
+    }
+
+    fn open(&self) -> Result<impl Read, std::io::Error> {
+        let mut fd = volume::open(&self.path)?;
+        fd.seek(SeekFrom::Start(self.member.unwrap_or(0)))?;
+
+        Result::Ok(fd.take(self.size))
+    }
+}
+
+/// the payload of a .pncsum archive, or split archive, described by its .ncsum entry
+fn packed(file: &Path) -> Result<Found, std::io::Error> {
+    let info = FileInfo::load(&file.to_path_buf())?;
+    let meta = std::fs::metadata(file)?;
+    let mut fd = volume::open(file)?;
+
+    let member = match archive::members(&mut fd)?
+        .into_iter()
+        .find(|m| m.is_payload())
+    {
+        Some(m) => m,
+        None => {
+            return Result::Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the archive does not contain the file",
+            ))
+        }
+    };
+
+    Result::Ok(Found {
+        path: file.to_path_buf(),
+        member: Some(member.data_offset),
+        size: member.size,
+        hash: info.algorithm.is_md5().then(|| info.hash.clone()),
+        old_name: Some(info.old_name),
+        inode: (meta.dev(), meta.ino()),
+    })
+}
+
+/// every copy inside `paths`, files described by .ncsum files and collection
+/// indexes being taken once, with their original name
+fn collect(paths: &[PathBuf], recursive: bool) -> Result<Vec<Found>, std::io::Error> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        match path.is_dir() {
+            true => files.extend(list_files(path, recursive)?),
+            false => files.push(path.clone()),
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut described = HashSet::new();
+
+    for file in files.iter() {
+        let sfname = file.to_string_lossy();
+
+        let infos = if sfname.ends_with(".ncsum") {
+            FileInfo::load(file).map(|i| vec![i])
+        } else if sfname.ends_with(INDEX_NAME) {
+            Index::load(file).map(|i| i.files)
+        } else if sfname.ends_with(".pncsum") || volume::is_manifest(file) {
+            match packed(file) {
+                Ok(f) => found.push(f),
+                Err(e) => println!("{:?}: {e}", file),
+            };
+
+            continue;
+        } else {
+            continue;
+        };
+
+        let infos = match infos {
+            Ok(i) => i,
+            Err(e) => {
+                println!("{:?}: {e}", file);
+                continue;
+            }
+        };
+
+        for info in infos {
+            let path = PathBuf::from(&info.new_name);
+
+            // dangling records are for audit to report
+            let (meta, canonical) = match (std::fs::metadata(&path), std::fs::canonicalize(&path)) {
+                (Ok(m), Ok(c)) => (m, c),
+                _ => continue,
+            };
+
+            described.insert(canonical);
+            found.push(Found {
+                path,
+                member: None,
+                size: meta.len(),
+                old_name: Some(info.old_name),
+                hash: info.algorithm.is_md5().then_some(info.hash),
+                inode: (meta.dev(), meta.ino()),
+            });
+        }
+    }
+
+    for file in files {
+        if is_ncsum_file(&file.to_string_lossy()) {
+            continue;
+        }
+
+        let meta = match std::fs::metadata(&file) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("{:?}: {e}", file);
+                continue;
+            }
+        };
+
+        if !meta.is_file() || std::fs::canonicalize(&file).is_ok_and(|c| described.contains(&c)) {
+            continue;
+        }
+
+        found.push(Found {
+            path: file,
+            member: None,
+            size: meta.len(),
+            old_name: None,
+            hash: None,
+            inode: (meta.dev(), meta.ino()),
+        });
+    }
+
+    Result::Ok(found)
+}
+
+/// groups copies of the same content, only hashing the ones that share their size with another
+fn group(found: Vec<Found>) -> Vec<Vec<Found>> {
+    let mut sizes: HashMap<u64, Vec<Found>> = HashMap::new();
+
+    for f in found {
+        // empty files are all the same, and take no space anyway
+        if f.size > 0 {
+            sizes.entry(f.size).or_default().push(f);
+        }
+    }
+
+    let mut sets = Vec::new();
+
+    for (_, same_size) in sizes {
+        if same_size.len() < 2 {
+            continue;
+        }
+
+        let mut hashes: HashMap<String, Vec<Found>> = HashMap::new();
+
+        for mut f in same_size {
+            if f.hash.is_none() {
+                match f
+                    .open()
+                    .and_then(|mut fd| try_get_hash(&mut fd, Algorithm::Md5))
+                {
+                    Ok(h) => f.hash = Some(h),
+                    Err(e) => {
+                        println!("{:?}: {e}", f.path);
+                        continue;
+                    }
+                };
+            }
+
+            hashes
+                .entry(f.hash.clone().unwrap_or_default())
+                .or_default()
+                .push(f);
+        }
+
+        for (_, mut set) in hashes {
+            set.sort_by(|a, b| (&a.path, a.member).cmp(&(&b.path, b.member)));
+            set.dedup_by(|a, b| a.path == b.path && a.member == b.member);
+
+            if copies(&set) > 1 {
+                sets.push(set);
+            }
+        }
+    }
+
+    sets.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+
+    sets
+}
+
+/// copies actually taking space, hardlinked files counting once
+fn copies(set: &[Found]) -> usize {
+    set.iter()
+        .map(|f| (f.inode, f.member))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// compares two copies byte by byte, before one is replaced with a link to the other
+fn same_content(a: &Found, b: &Found) -> Result<bool, std::io::Error> {
+    let (mut a, mut b) = (a.open()?, b.open()?);
+    let (mut abuf, mut bbuf) = (vec![0; 1024 * 1024], vec![0; 1024 * 1024]);
+
+    loop {
+        let s = a.read(&mut abuf)?;
+
+        if s == 0 {
+            return Result::Ok(b.read(&mut bbuf)? == 0);
+        }
+
+        b.read_exact(&mut bbuf[..s])?;
+
+        if abuf[..s] != bbuf[..s] {
+            return Result::Ok(false);
+        }
+    }
+}
+
+/// replaces `file` with a link to `keep`, through a temporary name so `file` is never missing
+fn replace_with_link(keep: &Path, file: &Path, link: Link) -> Result<(), std::io::Error> {
+    let tname = PathBuf::from(format!("{}.tncsum", file.to_string_lossy()));
+
+    match link {
+        Link::Hardlink => std::fs::hard_link(keep, &tname)?,
+        Link::Reflink => reflink_copy::reflink(keep, &tname)?,
+    };
+
+    std::fs::rename(&tname, file).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tname);
+    })
+}
+
+/// reports the sets of copies of the same content found inside `paths`, replacing
+/// the copies that are plain files with links to the first one when `link` is set
+pub fn dupes(paths: &[PathBuf], recursive: bool, link: Option<Link>) -> Result<(), std::io::Error> {
+    let sets = group(collect(paths, recursive)?);
+    let (mut redundant, mut wasted) = (0, 0);
+
+    for set in sets.iter() {
+        let extra = copies(set) - 1;
+
+        redundant += extra;
+        wasted += extra as u64 * set[0].size;
+
+        println!(
+            "{}, {} bytes:",
+            set[0].hash.clone().unwrap_or_default(),
+            set[0].size
+        );
+
+        for f in set {
+            println!("  {}", f.describe());
+        }
+
+        let (link, keep) = match (link, set.iter().find(|f| f.member.is_none())) {
+            (Some(l), Some(k)) => (l, k),
+            _ => continue,
+        };
+
+        for f in set
+            .iter()
+            .filter(|f| f.member.is_none() && f.inode != keep.inode)
+        {
+            let linked = same_content(keep, f).and_then(|same| match same {
+                true => replace_with_link(&keep.path, &f.path, link),
+                false => Result::Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the content differs, despite the hashes matching",
+                )),
+            });
+
+            let verb = match link {
+                Link::Hardlink => "hardlinked",
+                Link::Reflink => "reflinked",
+            };
+
+            match linked {
+                Ok(_) => println!("  {:?}: {verb} to {:?}", f.path, keep.path),
+                Err(e) => println!("  {:?}: {e}", f.path),
+            }
+        }
+    }
+
+    println!(
+        "{} sets of duplicates, {redundant} redundant copies, {wasted} bytes",
+        sets.len()
+    );
+
+    Result::Ok(())
+}
@@ -0,0 +1,178 @@
+//! encodings of .ncsum files and collection indexes, told apart when reading them,
+//! so files written in any of them can be mixed
+
+use std::io::Read;
+
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// compact JSON
+    #[default]
+    Json,
+    /// indented JSON, easier to read and diff
+    JsonPretty,
+    /// binary CBOR, smaller for large indexes
+    Cbor,
+    Toml,
+}
+
+/// TOML documents are tables, so lists are kept under a `files` key
+#[derive(Serialize, Deserialize)]
+struct Table<T> {
+    files: T,
+}
+
+fn invalid(e: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Format {
+    /// guesses the encoding of `data`, CBOR being the only one that isn't text
+    pub fn detect(data: &[u8]) -> Self {
+        let text = match std::str::from_utf8(data) {
+            Ok(t) => t.trim_start(),
+            Err(_) => return Format::Cbor,
+        };
+
+        match text.chars().next() {
+            Some('{') if text.contains("\n  ") => Format::JsonPretty,
+            Some('{') => Format::Json,
+            // a JSON list, or a TOML table header
+            Some('[') if serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok() => {
+                match text.contains("\n  ") {
+                    true => Format::JsonPretty,
+                    false => Format::Json,
+                }
+            }
+            _ => Format::Toml,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Format::Json => Result::Ok(serde_json::to_vec(value)?),
+            Format::JsonPretty => Result::Ok(serde_json::to_vec_pretty(value)?),
+            Format::Cbor => serde_cbor::to_vec(value).map_err(invalid),
+            Format::Toml => toml::to_string(value)
+                .map(String::into_bytes)
+                .map_err(invalid),
+        }
+    }
+
+    /// like `encode`, for lists
+    pub fn encode_list<T: Serialize>(&self, list: &[T]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Format::Toml => self.encode(&Table { files: list }),
+            _ => self.encode(&list),
+        }
+    }
+}
+
+/// decodes `data`, whatever its encoding
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, std::io::Error> {
+    match Format::detect(data) {
+        Format::Json | Format::JsonPretty => Result::Ok(serde_json::from_slice(data)?),
+        Format::Cbor => serde_cbor::from_slice(data).map_err(invalid),
+        Format::Toml => {
+            let text = std::str::from_utf8(data).map_err(invalid)?;
+
+            toml::from_str(text).map_err(invalid)
+        }
+    }
+}
+
+/// like `decode`, for lists
+pub fn decode_list<T: DeserializeOwned>(data: &[u8]) -> Result<Vec<T>, std::io::Error> {
+    match Format::detect(data) {
+        Format::Toml => decode::<Table<Vec<T>>>(data).map(|t| t.files),
+        _ => decode(data),
+    }
+}
+
+/// reads everything from `reader` and decodes it
+pub fn read<T: DeserializeOwned>(reader: &mut (impl Read + ?Sized)) -> Result<T, std::io::Error> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    decode(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Item {
+        hash: String,
+        size: u64,
+    }
+
+    fn items() -> Vec<Item> {
+        vec![
+            Item {
+                hash: String::from("b1946ac92492d2347c6235b4d2611184"),
+                size: 6,
+            },
+            Item {
+                hash: String::from("d41d8cd98f00b204e9800998ecf8427e"),
+                size: 0,
+            },
+        ]
+    }
+
+    const FORMATS: [Format; 4] = [Format::Json, Format::JsonPretty, Format::Cbor, Format::Toml];
+
+    #[test]
+    fn detects_json() {
+        assert_eq!(Format::detect(br#"{"hash":"ab","size":1}"#), Format::Json);
+        assert_eq!(Format::detect(b"  [1, 2]\n"), Format::Json);
+        assert_eq!(Format::detect(b"[]"), Format::Json);
+    }
+
+    #[test]
+    fn detects_pretty_json() {
+        assert_eq!(
+            Format::detect(b"{\n  \"hash\": \"ab\",\n  \"size\": 1\n}"),
+            Format::JsonPretty
+        );
+        assert_eq!(Format::detect(b"[\n  1,\n  2\n]"), Format::JsonPretty);
+    }
+
+    #[test]
+    fn detects_cbor() {
+        assert_eq!(Format::detect(&[0xa0]), Format::Cbor);
+        assert_eq!(Format::detect(&[0x82, 0x01, 0x02]), Format::Cbor);
+    }
+
+    #[test]
+    fn detects_toml() {
+        assert_eq!(Format::detect(b"hash = \"ab\"\nsize = 1\n"), Format::Toml);
+        assert_eq!(Format::detect(b"[[files]]\nsize = 1\n"), Format::Toml);
+        assert_eq!(Format::detect(b"[files]\nsize = 1\n"), Format::Toml);
+    }
+
+    #[test]
+    fn detects_what_it_encodes() {
+        for format in FORMATS {
+            let item = format.encode(&items()[0]).unwrap();
+            let list = format.encode_list(&items()).unwrap();
+
+            assert_eq!(Format::detect(&item), format);
+            assert_eq!(Format::detect(&list), format);
+            assert_eq!(decode::<Item>(&item).unwrap(), items()[0]);
+            assert_eq!(decode_list::<Item>(&list).unwrap(), items());
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode::<Item>(b"").is_err());
+        assert!(decode::<Item>(b"not a description").is_err());
+        assert!(decode::<Item>(b"{\"hash\": ").is_err());
+        assert!(decode::<Item>(&[0xff, 0x00, 0xfe]).is_err());
+        assert!(decode_list::<Item>(b"[1, 2").is_err());
+    }
+}
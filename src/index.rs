@@ -0,0 +1,92 @@
+//! a single `collection.ncsum-index` file holding the `FileInfo` of every file
+//! of a directory or tree, as an alternative to one .ncsum file per file
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::format::{self, Format};
+use crate::FileInfo;
+
+pub const INDEX_NAME: &str = "collection.ncsum-index";
+
+pub struct Index {
+    pub path: PathBuf,
+    pub files: Vec<FileInfo>,
+    /// the encoding the index is written in, the one it was read in by default
+    pub format: Format,
+}
+
+impl Index {
+    /// opens the index at `path`, or the one inside `path` if it is a directory
+    ///
+    /// a missing index is treated as an empty one
+    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
+        let path = if path.is_dir() {
+            path.join(INDEX_NAME)
+        } else {
+            path.to_path_buf()
+        };
+
+        let (files, format) = match std::fs::read(&path) {
+            Ok(data) => (format::decode_list(&data)?, Format::detect(&data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Vec::new(), Format::default()),
+            Err(e) => return Result::Err(e),
+        };
+
+        Result::Ok(Self {
+            path,
+            files,
+            format,
+        })
+    }
+
+    /// replaces the index on disk, through a temporary file so it is never left half written
+    ///
+    /// an index without records is removed
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        if self.files.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Result::Err(e),
+                _ => Result::Ok(()),
+            };
+        }
+
+        let tname = PathBuf::from(format!("{}.tncsum", self.path.to_string_lossy()));
+        let mut fd = File::create(&tname)?;
+
+        fd.write_all(&self.format.encode_list(&self.files)?)?;
+        fd.sync_all()?;
+        std::fs::rename(&tname, &self.path)
+    }
+
+    /// adds a record, replacing any previous one for the same file
+    ///
+    /// files sharing an object of a content-addressed store keep a record each
+    pub fn add(&mut self, info: FileInfo) {
+        self.files
+            .retain(|i| i.new_name != info.new_name || i.old_name != info.old_name);
+        self.files.push(info);
+    }
+
+    pub fn remove(&mut self, info: &FileInfo) {
+        self.files.retain(|i| {
+            i.new_name != info.new_name || i.hash != info.hash || i.old_name != info.old_name
+        });
+    }
+
+    /// the records matching `files`, given either by their hashed name or by their hash,
+    /// or every record when `files` is empty
+    pub fn select(&self, files: &[PathBuf]) -> Vec<FileInfo> {
+        self.files
+            .iter()
+            .filter(|i| {
+                files.is_empty()
+                    || files
+                        .iter()
+                        .any(|f| f == Path::new(&i.new_name) || f.as_os_str() == i.hash.as_str())
+            })
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,292 @@
+//! md5 digests written by versions before export-manifest was added, which hashed
+//! every 1 MiB read in full, the file being padded with zeros up to a multiple of 1 MiB
+//!
+//! files described with such a digest no longer match their hash, but they aren't
+//! damaged: every command comparing a file with its description accepts them, and
+//! `migrate` rewrites their descriptions with the actual hash
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::SigningKey;
+
+use crate::format::Format;
+use crate::{cp, recovery, sign, volume};
+use crate::{Algorithm, FileInfo, Hasher};
+
+const CHUNK: u64 = 1024 * 1024;
+
+/// hashes data with an algorithm, and the way older versions did too when the
+/// expected hash may be a legacy digest
+pub struct Verifier {
+    hasher: Hasher,
+    /// the legacy digest so far, and the amount of data consumed
+    legacy: Option<(md5::Context, u64)>,
+}
+
+impl Verifier {
+    /// a verifier for the file described by `info`
+    pub fn new(info: &FileInfo) -> Self {
+        Self::with(info.algorithm, info.may_be_legacy())
+    }
+
+    /// a verifier for `algorithm`, computing the legacy digest as well when `legacy`
+    /// is set and the algorithm is md5
+    pub fn with(algorithm: Algorithm, legacy: bool) -> Self {
+        Self {
+            hasher: Hasher::new(algorithm),
+            legacy: (legacy && algorithm.is_md5()).then(|| (md5::Context::new(), 0)),
+        }
+    }
+
+    pub fn consume(&mut self, data: &[u8]) {
+        self.hasher.consume(data);
+
+        if let Some((context, len)) = &mut self.legacy {
+            context.consume(data);
+            *len += data.len() as u64;
+        }
+    }
+
+    pub fn finish(self) -> Hashed {
+        let legacy = self.legacy.map(|(mut context, len)| {
+            let mut padding = (CHUNK - len % CHUNK) % CHUNK;
+            let zeros = [0; 64 * 1024];
+
+            while padding > 0 {
+                let n = padding.min(zeros.len() as u64);
+                context.consume(&zeros[..n as usize]);
+                padding -= n;
+            }
+
+            format!("{:x}", context.compute())
+        });
+
+        Hashed {
+            hash: self.hasher.finish(),
+            legacy,
+        }
+    }
+}
+
+/// the hash of some data, and its legacy digest when it was computed
+pub struct Hashed {
+    pub hash: String,
+    legacy: Option<String>,
+}
+
+impl Hashed {
+    /// whether the data has the `expected` hash, or legacy digest
+    pub fn matches(&self, expected: &str) -> bool {
+        self.hash == expected || self.is_legacy(expected)
+    }
+
+    /// whether the data only matches `expected` as a legacy digest
+    pub fn is_legacy(&self, expected: &str) -> bool {
+        self.hash != expected && self.legacy.as_deref() == Some(expected)
+    }
+}
+
+impl From<String> for Hashed {
+    fn from(hash: String) -> Self {
+        Self { hash, legacy: None }
+    }
+}
+
+/// hashes everything `fd` reads, for comparison with a hash made with `algorithm`
+/// that may be a legacy digest when `legacy` is set
+pub fn hash_with(
+    fd: &mut impl Read,
+    algorithm: Algorithm,
+    legacy: bool,
+) -> Result<Hashed, std::io::Error> {
+    let mut verifier = Verifier::with(algorithm, legacy);
+    let mut buffer = vec![0; 1024 * 1024];
+
+    loop {
+        let s = fd.read(&mut buffer)?;
+
+        if s == 0 {
+            break;
+        }
+
+        verifier.consume(&buffer[..s]);
+    }
+
+    Result::Ok(verifier.finish())
+}
+
+/// hashes everything `fd` reads, for comparison with the description `info`
+pub fn hash(fd: &mut impl Read, info: &FileInfo) -> Result<Hashed, std::io::Error> {
+    hash_with(fd, info.algorithm, info.may_be_legacy())
+}
+
+/// the actual hash of the file described by the .ncsum file, or packed in the archive
+/// `file`, which the file is read for when the description may hold a legacy digest
+pub fn actual_hash(file: &Path, info: &FileInfo) -> Result<String, std::io::Error> {
+    if !info.may_be_legacy() {
+        return Result::Ok(info.hash.clone());
+    }
+
+    let hashed = match file.to_string_lossy().ends_with(".pncsum") || volume::is_manifest(file) {
+        true => cp::packed_hash(volume::open(file)?, info)?,
+        false => hash(&mut File::open(cp::payload(file, info))?, info)?,
+    };
+
+    match hashed.is_legacy(&info.hash) {
+        true => Result::Ok(hashed.hash),
+        false => Result::Ok(info.hash.clone()),
+    }
+}
+
+/// the actual hash of the file at `payload`, described by `info` with a legacy
+/// digest, `None` when the description holds its actual hash already
+fn actual(payload: &Path, info: &FileInfo) -> Result<Option<String>, std::io::Error> {
+    if !info.may_be_legacy() {
+        return Result::Ok(None);
+    }
+
+    let hashed = hash(&mut File::open(payload)?, info)?;
+
+    match (hashed.is_legacy(&info.hash), hashed.matches(&info.hash)) {
+        (true, _) => Result::Ok(Some(hashed.hash)),
+        (false, true) => Result::Ok(None),
+        (false, false) => Result::Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the file does not match its hash",
+        )),
+    }
+}
+
+/// `path` named after `hash` instead of the legacy `digest`, when it is named after it
+fn renamed(path: &Path, digest: &str, hash: &str) -> PathBuf {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) if n.starts_with(digest) => {
+            path.with_file_name(format!("{hash}{}", &n[digest.len()..]))
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// describes the file at `payload` with its actual `hash` instead of the legacy
+/// digest of `info`, renaming it after the hash if it was named after the digest
+fn rehashed(info: &FileInfo, payload: &Path, hash: String) -> Result<FileInfo, std::io::Error> {
+    let rename = |name: &str| {
+        renamed(Path::new(name), &info.hash, &hash)
+            .to_string_lossy()
+            .to_string()
+    };
+
+    let mut migrated = info.clone();
+    migrated.new_name = rename(&info.new_name);
+    migrated.ncsum_name = rename(&info.ncsum_name);
+
+    let payload_to = renamed(payload, &info.hash, &hash);
+    migrated.hash = hash;
+
+    if payload_to != payload {
+        std::fs::rename(payload, &payload_to)?;
+    }
+
+    Result::Ok(migrated)
+}
+
+/// migrates a description holding a legacy digest, of an index record, returning
+/// `None` when it holds the actual hash already
+pub fn migrate_record(info: &FileInfo) -> Result<Option<FileInfo>, std::io::Error> {
+    let payload = PathBuf::from(&info.new_name);
+
+    match actual(&payload, info)? {
+        Some(h) => rehashed(info, &payload, h).map(Some),
+        None => Result::Ok(None),
+    }
+}
+
+/// migrates the description of the .ncsum file `sidecar`, which is written again in
+/// its encoding and renamed along with its file, its recovery data and its signature
+///
+/// a signed .ncsum file is signed again with `key`, and left as it is without one
+pub fn migrate_sidecar(
+    sidecar: &Path,
+    key: Option<&SigningKey>,
+) -> Result<Option<FileInfo>, std::io::Error> {
+    let info = FileInfo::load(&sidecar.to_path_buf())?;
+    let payload = cp::payload(sidecar, &info);
+
+    let hash = match actual(&payload, &info)? {
+        Some(h) => h,
+        None => return Result::Ok(None),
+    };
+
+    let signature = sign::signature_name(sidecar);
+
+    if key.is_none() && signature.exists() {
+        return Result::Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "the .ncsum file is signed, a key is needed to sign it again",
+        ));
+    }
+
+    let format = Format::detect(&std::fs::read(sidecar)?);
+    let migrated = rehashed(&info, &payload, hash)?;
+    let sidecar_to = renamed(sidecar, &info.hash, &migrated.hash);
+
+    // written where the .ncsum file is, wherever it was recorded
+    let tname = PathBuf::from(format!("{}.tncsum", sidecar_to.to_string_lossy()));
+
+    std::fs::write(&tname, format.encode(&migrated)?)?;
+    std::fs::rename(&tname, &sidecar_to)?;
+
+    let recovery_from = cp::recovery(sidecar);
+
+    if recovery_from.exists() {
+        recovery::rehash(&recovery_from, &migrated.hash)?;
+        std::fs::rename(&recovery_from, cp::recovery(&sidecar_to))?;
+    }
+
+    if sidecar_to != sidecar {
+        std::fs::remove_file(sidecar)?;
+    }
+
+    if let Some(k) = key.filter(|_| signature.exists()) {
+        if sign::signature_name(&sidecar_to) != signature {
+            std::fs::remove_file(&signature)?;
+        }
+
+        sign::sign_file(&sidecar_to, k)?;
+    }
+
+    Result::Ok(Some(migrated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_digest_pads_to_whole_chunks() {
+        let hashed = hash_with(&mut &b"hello\n"[..], Algorithm::Md5, true).unwrap();
+
+        assert_eq!(hashed.hash, "b1946ac92492d2347c6235b4d2611184");
+        assert!(hashed.is_legacy("179d926cc3ac2c62beb227bb4c3afb66"));
+        assert!(hashed.matches("b1946ac92492d2347c6235b4d2611184"));
+    }
+
+    #[test]
+    fn whole_chunks_and_empty_files_have_no_padding() {
+        for len in [0, CHUNK as usize, 2 * CHUNK as usize] {
+            let data = vec![7; len];
+            let hashed = hash_with(&mut &data[..], Algorithm::Md5, true).unwrap();
+
+            assert_eq!(hashed.legacy.as_deref(), Some(hashed.hash.as_str()));
+        }
+    }
+
+    #[test]
+    fn legacy_digest_only_for_md5() {
+        let hashed = hash_with(&mut &b"hello\n"[..], Algorithm::Sha256, true).unwrap();
+
+        assert!(!hashed.matches("179d926cc3ac2c62beb227bb4c3afb66"));
+    }
+}
@@ -0,0 +1,57 @@
+//! keyed HMAC-SHA256 of the description of a file, embedded in it, so a .ncsum file
+//! can't be changed to match a tampered file by anyone without the secret key
+//!
+//! the HMAC covers the description serialized as compact JSON, with only the file
+//! names of its paths, so it stays valid when files are moved or copied elsewhere
+
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::sign::{from_hex, to_hex};
+use crate::FileInfo;
+
+/// reads a secret key file, the whole content of which is the key
+pub fn load_key(file: &Path) -> Result<Vec<u8>, std::io::Error> {
+    let key = std::fs::read(file)?;
+
+    match key.is_empty() {
+        true => Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{:?} is empty", file),
+        )),
+        false => Result::Ok(key),
+    }
+}
+
+fn mac(info: &FileInfo, key: &[u8]) -> Result<Hmac<Sha256>, std::io::Error> {
+    let mut info = info.with_file_names();
+    info.hmac = None;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(serde_json::to_string(&info)?.as_bytes());
+
+    Result::Ok(mac)
+}
+
+/// embeds the HMAC of the description in it
+pub fn seal(info: &mut FileInfo, key: &[u8]) -> Result<(), std::io::Error> {
+    info.hmac = Some(to_hex(&mac(info, key)?.finalize().into_bytes()));
+
+    Result::Ok(())
+}
+
+/// why the description can't be trusted with this key, if it can't
+pub fn verify(info: &FileInfo, key: &[u8]) -> Result<Option<&'static str>, std::io::Error> {
+    let expected: [u8; 32] = match info.hmac.as_deref().map(from_hex) {
+        Some(Some(h)) => h,
+        Some(None) => return Result::Ok(Some("the HMAC is malformed")),
+        None => return Result::Ok(Some("no HMAC")),
+    };
+
+    match mac(info, key)?.verify_slice(&expected) {
+        Ok(_) => Result::Ok(None),
+        Err(_) => Result::Ok(Some("the HMAC doesn't match")),
+    }
+}
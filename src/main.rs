@@ -1,11 +1,35 @@
-use std::io::Read;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::{fs::File, io::Write};
 
-use clap::{Parser, Subcommand};
-use cpio::{write_cpio, NewcBuilder};
+use clap::{Parser, Subcommand, ValueEnum};
+use cpio::NewcBuilder;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+mod archive;
+mod audit;
+mod blocks;
+mod check;
+mod cp;
+mod crypt;
+mod diff;
+mod dupes;
+mod format;
+mod index;
+mod legacy;
+mod mac;
+mod manifest;
+mod quarantine;
+mod recovery;
+mod sign;
+mod state;
+mod store;
+mod sync;
+mod volume;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -18,18 +42,79 @@ struct Args {
 enum Commands {
     /// gets the hashes of the provided files
     GetHash {
+        /// read checksums from the FILEs and check them, like `md5sum -c`
+        #[arg(short = 'c', long = "check", default_value_t = false)]
+        check: bool,
+
+        /// algorithm of GNU style lines, guessed from the digest length by default
+        #[arg(short = 'a', long = "algorithm", value_enum)]
+        algorithm: Option<Algorithm>,
+
+        /// don't print OK for each successfully verified file
+        #[arg(
+            short = 'q',
+            long = "quiet",
+            default_value_t = false,
+            requires = "check"
+        )]
+        quiet: bool,
+
+        /// don't output anything, the exit status shows success
+        #[arg(long = "status", default_value_t = false, requires = "check")]
+        status: bool,
+
+        /// don't fail or report status for missing files
+        #[arg(long = "ignore-missing", default_value_t = false, requires = "check")]
+        ignore_missing: bool,
+
         #[arg(value_name = "FILE")]
         files: Vec<PathBuf>,
     },
 
     /// rename the file to its hash and create a separate file containing both the hash and the original file name
     Name {
+        /// also store the hash of every block of this size, so check --detail can tell where a file changed
+        #[arg(short = 'b', long = "block-size", value_name = "SIZE", value_parser = blocks::parse_size)]
+        block_size: Option<u64>,
+
+        /// record the files in this collection index (or the one inside this directory) instead of creating .ncsum files
+        #[arg(short = 'i', long = "index", value_name = "INDEX")]
+        index: Option<PathBuf>,
+
+        /// move the files into this content-addressed store, under DIR/ab/cd/abcd…, instead of leaving them in place
+        #[arg(long = "store", value_name = "DIR")]
+        store: Option<PathBuf>,
+
+        /// secret key file, to embed an HMAC of every description made with it
+        #[arg(long = "hmac-key", value_name = "KEY", env = "NCSUM_HMAC_KEY")]
+        hmac_key: Option<PathBuf>,
+
+        /// encoding of the .ncsum files, json by default, an existing index keeps its own unless given
+        #[arg(long = "sidecar-format", value_enum)]
+        sidecar_format: Option<format::Format>,
+
         #[arg(value_name = "FILE")]
         files: Vec<PathBuf>,
     },
 
     /// takes a .ncsum file and uses it to return its respective file to its original state
     Rename {
+        /// restore files recorded in this collection index, given by hashed name or hash, all of them if none is given
+        #[arg(short = 'i', long = "index", value_name = "INDEX")]
+        index: Option<PathBuf>,
+
+        /// copy files out of a content-addressed store, leaving them there for other files with the same content
+        #[arg(short = 'k', long = "keep", default_value_t = false)]
+        keep: bool,
+
+        /// age identity file, holding X25519 secret keys, to decrypt encrypted archives with
+        #[arg(long = "identity", value_name = "FILE")]
+        identities: Vec<PathBuf>,
+
+        /// read the passphrase of encrypted archives from the first line of this file instead of NCSUM_PASSPHRASE
+        #[arg(long = "passphrase-file", value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
         #[arg(value_name = "FILE")]
         files: Vec<PathBuf>,
     },
@@ -42,47 +127,528 @@ enum Commands {
         #[arg(short = 's', long = "separate-mismatches", default_value_t = false)]
         separate_mismatches: bool,
 
+        /// tell which byte ranges of mismatching files changed, for files described with block hashes
+        #[arg(short = 'd', long = "detail", default_value_t = false)]
+        detail: bool,
+
+        /// put mismatching files aside in DIR/<expected hash>/ instead of next to their .ncsum file, implies -s
+        #[arg(short = 'q', long = "quarantine-dir", value_name = "DIR")]
+        quarantine_dir: Option<PathBuf>,
+
+        /// how mismatching files are put aside, implies -s
+        #[arg(short = 'm', long = "quarantine-mode", value_enum)]
+        quarantine_mode: Option<quarantine::Mode>,
+
+        /// check files recorded in this collection index, given by hashed name or hash, all of them if none is given
+        #[arg(short = 'i', long = "index", value_name = "INDEX")]
+        index: Option<PathBuf>,
+
+        /// only verify a random PCT of the blocks of each file, files without block hashes are checked with a PCT chance
+        #[arg(long = "sample", value_name = "PCT", value_parser = check::parse_fraction)]
+        sample: Option<f64>,
+
+        /// stop starting new files after this long, like 90s, 30m, 2h or 1d, and continue from there next time
+        #[arg(long = "budget", value_name = "DURATION", value_parser = check::parse_duration)]
+        budget: Option<std::time::Duration>,
+
+        /// file remembering where a check with a budget stopped
+        #[arg(long = "cursor", value_name = "FILE", default_value = ".ncsum-cursor")]
+        cursor: PathBuf,
+
+        /// only check files that weren't verified within this long, like 12h or 30d
+        #[arg(long = "older-than", value_name = "DURATION", value_parser = check::parse_duration, requires = "state")]
+        older_than: Option<std::time::Duration>,
+
+        /// record when each file was checked in this state file, or in the directory holding it
+        #[arg(long = "state", value_name = "STATE")]
+        state: Option<PathBuf>,
+
+        /// age identity file to decrypt encrypted archives with, without keys only their encrypted data is checked
+        #[arg(long = "identity", value_name = "FILE")]
+        identities: Vec<PathBuf>,
+
+        /// read the passphrase of encrypted archives from the first line of this file instead of NCSUM_PASSPHRASE
+        #[arg(long = "passphrase-file", value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// reject .ncsum and .pncsum files that aren't signed by one of the --public-key keys
+        #[arg(
+            long = "require-signature",
+            default_value_t = false,
+            requires = "public_keys",
+            conflicts_with = "index"
+        )]
+        require_signature: bool,
+
+        /// public key file of a trusted signer
+        #[arg(long = "public-key", value_name = "KEY")]
+        public_keys: Vec<PathBuf>,
+
+        /// secret key file, to reject descriptions without a matching HMAC before trusting their hash
+        #[arg(long = "hmac-key", value_name = "KEY", env = "NCSUM_HMAC_KEY")]
+        hmac_key: Option<PathBuf>,
+
         #[arg(value_name = "FILE")]
         files: Vec<PathBuf>,
     },
 
+    /// tells when files were last checked and verified, from the state check keeps
+    Status {
+        /// state file, or the directory holding it
+        #[arg(long = "state", value_name = "STATE", default_value = ".")]
+        state: PathBuf,
+
+        /// files verified within this long are counted as covered
+        #[arg(long = "within", value_name = "DURATION", default_value = "30d", value_parser = check::parse_duration)]
+        within: std::time::Duration,
+
+        /// .ncsum or .pncsum files, or hashed names for index records, every file in the state if none is given
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// puts quarantined files back where check found them
+    Release {
+        /// quarantine directory holding the quarantine manifest
+        #[arg(
+            short = 'q',
+            long = "quarantine-dir",
+            value_name = "DIR",
+            default_value = "."
+        )]
+        quarantine_dir: PathBuf,
+
+        /// expected hashes of the files to release, all of them if none is given
+        #[arg(value_name = "HASH")]
+        hashes: Vec<String>,
+    },
+
     Pack {
+        /// pack the data read from stdin instead of the FILEs
+        #[arg(long = "stdin", default_value_t = false, requires = "name")]
+        stdin: bool,
+
+        /// original name of the file read from stdin
+        #[arg(long = "name", value_name = "NAME", requires = "stdin")]
+        name: Option<PathBuf>,
+
+        /// also store the hash of every block of this size, so check --detail can tell where a file changed
+        #[arg(short = 'b', long = "block-size", value_name = "SIZE", value_parser = blocks::parse_size, conflicts_with = "stdin")]
+        block_size: Option<u64>,
+
+        /// encrypt the packed files with age, to a passphrase unless recipients are given
+        #[arg(
+            short = 'e',
+            long = "encrypt",
+            default_value_t = false,
+            conflicts_with = "stdin"
+        )]
+        encrypt: bool,
+
+        /// encrypt to this X25519 public key, like age1…, implies --encrypt
+        #[arg(short = 'R', long = "recipient", value_name = "RECIPIENT", value_parser = crypt::parse_recipient, conflicts_with = "stdin")]
+        recipients: Vec<age::x25519::Recipient>,
+
+        /// read the passphrase from the first line of this file instead of NCSUM_PASSPHRASE
+        #[arg(long = "passphrase-file", value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// secret key file, to embed an HMAC of every description made with it
+        #[arg(long = "hmac-key", value_name = "KEY", env = "NCSUM_HMAC_KEY")]
+        hmac_key: Option<PathBuf>,
+
+        /// encoding of the .ncsum files, json by default, an existing index keeps its own unless given
+        #[arg(long = "sidecar-format", value_enum)]
+        sidecar_format: Option<format::Format>,
+
+        /// archive format, cpio unless the file is larger than 4 GiB, which needs tar
+        #[arg(long = "container", value_enum)]
+        container: Option<archive::Container>,
+
+        /// split the archives into volumes of this size, like 650M or 4G, described by a .vncsum manifest
+        #[arg(long = "volume-size", value_name = "SIZE", value_parser = blocks::parse_size)]
+        volume_size: Option<u64>,
+
+        /// write the archive of the single FILE here instead of <hash>.pncsum, - for stdout, which leaves the FILE in place
+        #[arg(
+            short = 'o',
+            long = "output",
+            value_name = "ARCHIVE",
+            conflicts_with = "volume_size"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// writes a md5sum/sha256sum compatible checksum list describing the provided .ncsum or .pncsum files
+    ExportManifest {
+        /// write BSD style lines, like `md5sum --tag`
+        #[arg(short = 't', long = "tag", default_value_t = false)]
+        tag: bool,
+
+        /// list the original file names instead of the hashed ones
+        #[arg(short = 'O', long = "original-names", default_value_t = false)]
+        original_names: bool,
+
+        /// write the list to this file instead of stdout
+        #[arg(short = 'o', long = "output", value_name = "SUMSFILE")]
+        output: Option<PathBuf>,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// looks for orphaned payloads, dangling .ncsum files, leftover temporaries and files whose name doesn't match their hash
+    Audit {
+        /// also scan subdirectories
+        #[arg(short = 'r', long = "recursive", default_value_t = false)]
+        recursive: bool,
+
+        /// create .ncsum files for orphaned payloads whose content matches their name and remove leftover temporaries
+        #[arg(short = 'f', long = "fix", default_value_t = false)]
+        fix: bool,
+
+        #[arg(value_name = "DIR", default_value = ".")]
+        dirs: Vec<PathBuf>,
+    },
+
+    /// stores Reed-Solomon recovery data for the files described by .ncsum files, in a .rncsum file next to them, or inside .pncsum archives
+    Protect {
+        /// size of the recovery data, relative to the size of the file
+        #[arg(short = 'r', long = "redundancy", default_value = "10%", value_parser = recovery::parse_redundancy)]
+        redundancy: f64,
+
+        /// size of the blocks the file is split into, chosen from the file size by default, at most 1M and the file size
+        #[arg(short = 'b', long = "block-size", value_name = "SIZE", value_parser = blocks::parse_size)]
+        block_size: Option<u64>,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// rebuilds the damaged blocks of files protected by the protect subcommand
+    Repair {
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// moves the content of .ncsum files into a collection index and removes them
+    ToIndex {
+        /// collection index, or directory holding it
+        #[arg(short = 'i', long = "index", value_name = "INDEX", default_value = ".")]
+        index: PathBuf,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// writes .ncsum files for records of a collection index and removes them from it
+    FromIndex {
+        /// collection index, or directory holding it
+        #[arg(short = 'i', long = "index", value_name = "INDEX", default_value = ".")]
+        index: PathBuf,
+
+        /// hashed names or hashes of the records to convert, all of them if none is given
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// creates .ncsum files from the entries of an existing md5sum/sha256sum checksum list
+    ImportManifest {
+        /// algorithm of GNU style lines, guessed from the digest length by default
+        #[arg(short = 'a', long = "algorithm", value_enum)]
+        algorithm: Option<Algorithm>,
+
+        /// also rename every listed file to its hash, like the name subcommand does
+        #[arg(short = 'r', long = "rename", default_value_t = false)]
+        rename: bool,
+
+        /// hash every listed file and skip the ones that do not match the list
+        #[arg(short = 'v', long = "verify", default_value_t = false)]
+        verify: bool,
+
+        #[arg(value_name = "SUMSFILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// names or packs the files dropped into a directory, once nothing writes to them anymore
+    Watch {
+        /// what to do with new files
+        #[arg(short = 'a', long = "action", value_enum, default_value_t)]
+        action: watch::Action,
+
+        /// how long a file has to go without writes before it is handled, like 10s or 1m
+        #[arg(short = 's', long = "stable", value_name = "DURATION", default_value = "5s", value_parser = check::parse_duration)]
+        stable: std::time::Duration,
+
+        /// also store the hash of every block of this size, so check --detail can tell where a file changed
+        #[arg(short = 'b', long = "block-size", value_name = "SIZE", value_parser = blocks::parse_size)]
+        block_size: Option<u64>,
+
+        /// secret key file, to embed an HMAC of every description made with it
+        #[arg(long = "hmac-key", value_name = "KEY", env = "NCSUM_HMAC_KEY")]
+        hmac_key: Option<PathBuf>,
+
+        /// encoding of the .ncsum files, json by default
+        #[arg(long = "sidecar-format", value_enum)]
+        sidecar_format: Option<format::Format>,
+
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+    },
+
+    /// finds files and packed files with the same content, by their hash
+    Dupes {
+        /// also scan subdirectories
+        #[arg(short = 'r', long = "recursive", default_value_t = false)]
+        recursive: bool,
+
+        /// replace duplicates with links to the first copy, packed files are left alone
+        #[arg(short = 'l', long = "link", value_enum)]
+        link: Option<dupes::Link>,
+
+        #[arg(value_name = "PATH", default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+
+    /// compares two directories, collection indexes or checksum lists by the hashes they describe
+    Diff {
+        /// also scan subdirectories
+        #[arg(short = 'r', long = "recursive", default_value_t = false)]
+        recursive: bool,
+
+        /// print the differences as JSON
+        #[arg(short = 'j', long = "json", default_value_t = false)]
+        json: bool,
+
+        #[arg(value_name = "A")]
+        a: PathBuf,
+
+        #[arg(value_name = "B")]
+        b: PathBuf,
+    },
+
+    /// copies the files described by .ncsum, .pncsum and .vncsum files whose content a directory is missing, verifying every copy
+    Sync {
+        /// also sync subdirectories
+        #[arg(short = 'r', long = "recursive", default_value_t = false)]
+        recursive: bool,
+
+        /// remove from DST the files whose content SRC doesn't have
+        #[arg(short = 'd', long = "delete", default_value_t = false)]
+        delete: bool,
+
+        /// only tell what would be copied and removed
+        #[arg(short = 'n', long = "dry-run", default_value_t = false)]
+        dry_run: bool,
+
+        #[arg(value_name = "SRC")]
+        src: PathBuf,
+
+        #[arg(value_name = "DST")]
+        dst: PathBuf,
+    },
+
+    /// copies files, hashing them on the way and reading the copies back, and describes the copies with .ncsum files
+    Cp {
+        /// algorithm for files without a .ncsum file
+        #[arg(short = 'a', long = "algorithm", value_enum, default_value_t)]
+        algorithm: Algorithm,
+
+        #[arg(value_name = "SRC", required = true)]
+        files: Vec<PathBuf>,
+
+        #[arg(value_name = "DST")]
+        dst: PathBuf,
+    },
+
+    /// writes the files packed in .pncsum archives, decrypting them if needed, and leaves the archives in place, - reads an archive from stdin
+    Extract {
+        /// write the files into this directory instead of under their original name
+        #[arg(short = 'd', long = "dir", value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// age identity file, holding X25519 secret keys, to decrypt encrypted archives with
+        #[arg(long = "identity", value_name = "FILE")]
+        identities: Vec<PathBuf>,
+
+        /// read the passphrase of encrypted archives from the first line of this file instead of NCSUM_PASSPHRASE
+        #[arg(long = "passphrase-file", value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// signs .ncsum files and .pncsum archives with an Ed25519 key, so changes to them can be told
+    Sign {
+        /// secret key file
+        #[arg(short = 'k', long = "key", value_name = "KEY")]
+        key: PathBuf,
+
+        /// create KEY and its public key, KEY.pub, first
+        #[arg(short = 'g', long = "generate", default_value_t = false)]
+        generate: bool,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// checks that .ncsum files and .pncsum archives are signed by a trusted key and weren't changed since
+    VerifySignature {
+        /// public key file of a trusted signer
+        #[arg(short = 'k', long = "public-key", value_name = "KEY", required = true)]
+        public_keys: Vec<PathBuf>,
+
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// lists the members of .pncsum archives, along with their container
+    List {
+        #[arg(value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
+
+    /// describes files described with the md5 digest of older versions with their actual hash, and renames them after it
+    Migrate {
+        /// migrate records of this collection index (or the one inside this directory), given by hashed name or hash, all of them if none is given
+        #[arg(short = 'i', long = "index", value_name = "INDEX")]
+        index: Option<PathBuf>,
+
+        /// secret key file, to sign the .ncsum files that were signed again
+        #[arg(short = 'k', long = "key", value_name = "KEY")]
+        key: Option<PathBuf>,
+
         #[arg(value_name = "FILE")]
         files: Vec<PathBuf>,
     },
 }
 
-fn get_hash(fd: &mut impl Read) -> String {
-    let mut file_context = md5::Context::new();
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Algorithm {
+    #[default]
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
 
-    loop {
-        let mut buffer = [0; 1024 * 1024];
+impl Algorithm {
+    fn is_md5(&self) -> bool {
+        *self == Algorithm::Md5
+    }
 
-        let s = match fd.read(&mut buffer) {
-            Ok(s) => s,
-            Err(e) => {
-                println!("{e}");
-                std::process::exit(1);
-            }
-        };
+    /// guesses the algorithm that produced a hex digest from its length
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(Algorithm::Md5),
+            40 => Some(Algorithm::Sha1),
+            64 => Some(Algorithm::Sha256),
+            128 => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// name used by BSD style (`--tag`) checksum lines
+    fn tag(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "MD5" => Some(Algorithm::Md5),
+            "SHA1" => Some(Algorithm::Sha1),
+            "SHA256" => Some(Algorithm::Sha256),
+            "SHA512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    fn consume(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(c) => c.consume(data),
+            Hasher::Sha1(c) => c.update(data),
+            Hasher::Sha256(c) => c.update(data),
+            Hasher::Sha512(c) => c.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(c) => format!("{:x}", c.compute()),
+            Hasher::Sha1(c) => format!("{:x}", c.finalize()),
+            Hasher::Sha256(c) => format!("{:x}", c.finalize()),
+            Hasher::Sha512(c) => format!("{:x}", c.finalize()),
+        }
+    }
+}
+
+fn get_hash(fd: &mut impl Read, algorithm: Algorithm) -> String {
+    match try_get_hash(fd, algorithm) {
+        Ok(h) => h,
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn try_get_hash(fd: &mut impl Read, algorithm: Algorithm) -> Result<String, std::io::Error> {
+    let mut file_context = Hasher::new(algorithm);
+    let mut buffer = vec![0; 1024 * 1024];
+
+    loop {
+        let s = fd.read(&mut buffer)?;
 
         if s == 0 {
             break;
         }
 
-        file_context.consume(buffer);
+        file_context.consume(&buffer[..s]);
     }
 
-    format!("{:x}", file_context.compute())
+    Result::Ok(file_context.finish())
 }
 
 trait NCSum {
     fn get_hash(&self) -> Result<String, std::io::Error>;
+    fn get_hash_with(&self, algorithm: Algorithm) -> Result<String, std::io::Error>;
     fn get_suffix(&self) -> String;
 }
 
 impl NCSum for PathBuf {
     fn get_hash(&self) -> Result<String, std::io::Error> {
+        self.get_hash_with(Algorithm::Md5)
+    }
+
+    fn get_hash_with(&self, algorithm: Algorithm) -> Result<String, std::io::Error> {
         let mut file = match File::open(self) {
             Ok(f) => f,
             Err(e) => {
@@ -91,7 +657,7 @@ impl NCSum for PathBuf {
             }
         };
 
-        Result::Ok(get_hash(&mut file))
+        try_get_hash(&mut file, algorithm)
     }
 
     fn get_suffix(&self) -> String {
@@ -101,579 +667,2536 @@ impl NCSum for PathBuf {
             .to_str()
             .expect("Error getting file name")
             .to_string();
-        let last_dot = file_name.rfind('.').expect("Error getting file suffix");
-        let ext = &file_name[last_dot..];
-
-        String::from(ext)
+        // files without a suffix keep just the hash as their name
+        match file_name.rfind('.') {
+            Some(last_dot) => String::from(&file_name[last_dot..]),
+            None => String::new(),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct FileInfo {
     hash: String,
+    #[serde(default, skip_serializing_if = "Algorithm::is_md5")]
+    algorithm: Algorithm,
     old_name: String,
     new_name: String,
     ncsum_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blocks: Option<blocks::Blocks>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<crypt::Encryption>,
+    /// HMAC of the description, made with a secret key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hmac: Option<String>,
 }
 
 impl FileInfo {
-    fn new(file: &PathBuf) -> Self {
-        let file_hash = match file.get_hash() {
-            Ok(s) => s,
+    /// describes `file` by its md5 hash, also storing the digests of every `block_size` bytes of the file when given
+    fn with_blocks(file: &PathBuf, block_size: Option<u64>) -> Self {
+        match Self::try_with_blocks(file, block_size) {
+            Ok(i) => i,
             Err(e) => {
                 println!("{e}");
                 std::process::exit(1);
             }
+        }
+    }
+
+    /// like `with_blocks`, returning errors instead of exiting
+    fn try_with_blocks(file: &PathBuf, block_size: Option<u64>) -> Result<Self, std::io::Error> {
+        let block_size = match block_size {
+            Some(b) => b,
+            None => return Result::Ok(Self::with_hash(file, file.get_hash()?, Algorithm::Md5)),
         };
 
+        let (file_hash, file_blocks) =
+            blocks::hash_blocks(&mut File::open(file)?, Algorithm::Md5, block_size)?;
+
+        let mut info = Self::with_hash(file, file_hash, Algorithm::Md5);
+        info.blocks = Some(file_blocks);
+
+        Result::Ok(info)
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// where the file lives inside a content-addressed store
+    fn store_name(&self, store: &Path) -> String {
+        let suffix = PathBuf::from(&self.old_name).get_suffix();
+
+        store::object_path(store, &self.hash, &suffix)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// describes `file` using an already known hash, the file itself doesn't need to exist
+    fn with_hash(file: &PathBuf, file_hash: String, algorithm: Algorithm) -> Self {
         let file_suffix = file.get_suffix();
+        let parent = file.parent().expect("Error getting file parent folder");
 
         let new_file_name = file_hash.clone() + file_suffix.as_str();
-        let new_file = file
-            .parent()
-            .expect("Error getting file parent folder")
-            .join(new_file_name);
-        let ncsum_file = file
-            .parent()
-            .expect("Error getting file parent folder")
-            .join(file_hash.clone() + ".ncsum");
+        let new_file = parent.join(new_file_name);
+        let ncsum_file = parent.join(file_hash.clone() + ".ncsum");
 
         Self {
             hash: file_hash,
+            algorithm,
             old_name: String::from(file.to_str().expect("Error getting file name")),
             new_name: String::from(new_file.to_str().expect("Error getting file name")),
             ncsum_name: String::from(ncsum_file.to_str().expect("Error getting file name")),
+            blocks: None,
+            encryption: None,
+            hmac: None,
         }
     }
-}
 
-fn main() {
-    let args = Args::parse();
+    /// reads the description stored in a .ncsum file or inside a .pncsum archive,
+    /// split into volumes or not
+    fn load(file: &PathBuf) -> Result<Self, std::io::Error> {
+        if !file.to_string_lossy().ends_with(".pncsum") && !volume::is_manifest(file) {
+            return format::read(&mut File::open(file)?);
+        }
 
-    match args.command {
-        Commands::GetHash { files } => {
-            for file in files {
-                let info = FileInfo::new(&file);
+        let mut info = None;
 
-                println!("{}  {}", info.hash, info.old_name);
+        archive::read_members(volume::open(file)?, |name, data| {
+            if name.ends_with(".ncsum") {
+                info = Some(format::read(data)?);
             }
-        }
 
-        Commands::Name { files } => {
-            for file in files {
-                let sfname = String::from(file.to_str().expect("Error getting file name"));
+            Result::Ok(info.is_none())
+        })?;
 
-                if !sfname.ends_with(".ncsum") || !sfname.ends_with(".pncsum") {
-                    let info = FileInfo::new(&file);
+        info.ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{:?} does not contain a .ncsum file", file),
+        ))
+    }
 
-                    let mut ncsum_file = match File::create(info.ncsum_name.clone()) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            panic!("{e}: {:?}", info.ncsum_name.clone());
-                        }
-                    };
+    /// the pax records kept along with the packed file in tar archives, as extended
+    /// attributes that tar tools can restore on the extracted file
+    fn pax_records(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "SCHILY.xattr.user.ncsum.algorithm",
+                self.algorithm.tag().to_string(),
+            ),
+            ("SCHILY.xattr.user.ncsum.hash", self.hash.clone()),
+        ]
+    }
 
-                    let json = match serde_json::to_string(&info) {
-                        Ok(j) => j,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+    /// the description with only the file names of its paths, which is what HMACs and
+    /// signatures cover, so they stay valid when files are moved or copied elsewhere
+    fn with_file_names(&self) -> Self {
+        let file_name = |path: &str| match Path::new(path).file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => path.to_string(),
+        };
 
-                    match ncsum_file.write_all(json.as_bytes()) {
-                        Ok(n) => n,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+        Self {
+            old_name: file_name(&self.old_name),
+            new_name: file_name(&self.new_name),
+            ncsum_name: file_name(&self.ncsum_name),
+            ..self.clone()
+        }
+    }
 
-                    match std::fs::rename(info.old_name.clone(), info.new_name.clone()) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+    /// whether the hash may be a `legacy` digest, which only md5 descriptions
+    /// without anything added since can have
+    fn may_be_legacy(&self) -> bool {
+        self.algorithm.is_md5()
+            && self.blocks.is_none()
+            && self.encryption.is_none()
+            && self.hmac.is_none()
+    }
 
-                    println!("{:?} -> {:?}", info.old_name, info.new_name);
-                }
-            }
-        }
+    /// writes the description to `ncsum_name`
+    fn save(&self) -> Result<(), std::io::Error> {
+        self.save_as(format::Format::default())
+    }
 
-        Commands::Rename { files } => {
-            for file in files {
-                let sfname = String::from(file.to_str().expect("Error getting file name"));
-                let mut fd: File;
-                let mut info = FileInfo {
-                    hash: String::new(),
-                    old_name: String::new(),
-                    new_name: String::new(),
-                    ncsum_name: String::new(),
-                };
+    /// writes the description to `ncsum_name`, in the given encoding
+    fn save_as(&self, format: format::Format) -> Result<(), std::io::Error> {
+        let mut fd = File::create(&self.ncsum_name)?;
 
-                let mut old_name = String::new();
+        fd.write_all(&format.encode(self)?)
+    }
+}
 
-                if sfname.ends_with(".ncsum") {
-                    fd = match File::open(sfname) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+/// names a file after its hash, next to a .ncsum file describing it, or moves it
+/// into a content-addressed store
+///
+/// the returned flag is false when the store already had the content, and the file was dropped
+fn name_file(
+    file: &PathBuf,
+    block_size: Option<u64>,
+    store: Option<&Path>,
+    hmac_key: Option<&[u8]>,
+    format: format::Format,
+) -> Result<(FileInfo, bool), std::io::Error> {
+    let mut info = FileInfo::try_with_blocks(file, block_size)?;
+
+    // files sharing an object of the store can't share a sidecar too
+    if let Some(s) = store {
+        info.new_name = info.store_name(s);
+        info.ncsum_name = info.old_name.clone() + ".ncsum";
+    }
 
-                    info = match serde_json::from_reader(fd) {
-                        Ok(j) => j,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+    // the HMAC covers the names, so it is made once they are final
+    if let Some(k) = hmac_key {
+        mac::seal(&mut info, k)?;
+    }
 
-                    old_name = info.old_name;
-                } else if sfname.ends_with(".pncsum") {
-                    fd = match File::open(sfname.clone()) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+    info.save_as(format)?;
 
-                    let mut out_fd = match File::create(sfname.replace(".pncsum", ".tncsum")) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            println!("{e}");
+    if store.is_none() {
+        std::fs::rename(&info.old_name, &info.new_name)?;
+
+        return Result::Ok((info, true));
+    }
+
+    let moved = match store::put(
+        Path::new(&info.old_name),
+        Path::new(&info.new_name),
+        &info.hash,
+        info.algorithm,
+    ) {
+        Ok(m) => m,
+        Err(e) => {
+            // the file stays where it was, undescribed
+            std::fs::remove_file(&info.ncsum_name)?;
+            return Result::Err(e);
+        }
+    };
+
+    Result::Ok((info, moved))
+}
+
+/// whether pack writes the archive to stdout
+fn is_stdout(output: Option<&Path>) -> bool {
+    output.is_some_and(|o| o.as_os_str() == "-")
+}
+
+/// where pack writes an archive
+#[derive(Clone, Copy, Default)]
+enum Destination<'a> {
+    /// `<hash>.pncsum`, next to the file
+    #[default]
+    Named,
+    /// this file, or stdout for `-`
+    Output(&'a Path),
+    /// volumes of this size, `<hash>.pncsum.001` and so on, and their `<hash>.vncsum` manifest
+    Volumes(u64),
+}
+
+impl Destination<'_> {
+    fn is_stdout(&self) -> bool {
+        match self {
+            Destination::Output(o) => is_stdout(Some(o)),
+            _ => false,
+        }
+    }
+}
+
+/// writes the .pncsum archive of the file described by `info`, whose data is read
+/// from `payload`, encrypting it for `recipients` and embedding an HMAC made with
+/// `hmac_key` when given
+///
+/// without a `container`, the archive is cpio unless the file is too large for it,
+/// and unless written to an output, it is named after the hash of the file, or of
+/// the encrypted data
+///
+/// returns the name of the archive, or of the manifest of its volumes
+fn write_pack(
+    info: &mut FileInfo,
+    payload: &Path,
+    recipients: Option<&crypt::Recipients>,
+    hmac_key: Option<&[u8]>,
+    format: format::Format,
+    container: Option<archive::Container>,
+    to: Destination,
+) -> Result<String, std::io::Error> {
+    if recipients.is_some() && info.hmac.is_some() && hmac_key.is_none() {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the description has an HMAC, which the encrypted archive can't have without its key",
+        ));
+    }
+
+    if let Some(k) = hmac_key {
+        mac::seal(info, k)?;
+    }
+
+    let tname = info.ncsum_name.replace(".ncsum", ".tncsum");
+    let ename = info.ncsum_name.replace(".ncsum", ".age.tncsum");
+
+    let mut tfile = File::create(&tname)?;
+    tfile.write_all(&format.encode(info)?)?;
+    tfile.flush()?;
+
+    // an encrypted file is packed along with its description, and the archive they
+    // make is what gets encrypted and packed, described by the encrypted data only
+    let (outer, payload) = match recipients {
+        Some(r) => {
+            let encrypted = r.encrypt(Path::new(&ename), info.algorithm, |out| {
+                let inputs = pack_inputs(info, &tname, payload)?;
+                let container = match container {
+                    Some(c) => c,
+                    None => archive::Container::fitting(std::fs::metadata(payload)?.len()),
+                };
+
+                archive::write(container, inputs, &mut &mut *out)
+            });
+
+            let hash = match encrypted {
+                Ok(h) => h,
+                Err(e) => {
+                    std::fs::remove_file(&tname)?;
+                    let _ = std::fs::remove_file(&ename);
+
+                    return Result::Err(e);
+                }
+            };
+
+            let dir = Path::new(&info.ncsum_name)
+                .parent()
+                .unwrap_or(Path::new(""));
+            let mut outer =
+                FileInfo::with_hash(&dir.join(format!("{hash}.age")), hash, info.algorithm);
+            outer.encryption = Some(crypt::Encryption { method: r.method() });
+
+            if let Some(k) = hmac_key {
+                mac::seal(&mut outer, k)?;
+            }
+
+            let mut tfile = File::create(&tname)?;
+            tfile.write_all(&format.encode(&outer)?)?;
+            tfile.flush()?;
+
+            (outer, Path::new(&ename))
+        }
+        None => (info.clone(), payload),
+    };
+
+    let pname = match to {
+        Destination::Output(o) => o.to_string_lossy().to_string(),
+        _ => outer.ncsum_name.replace(".ncsum", ".pncsum"),
+    };
+
+    let container = match container {
+        Some(c) => c,
+        None => archive::Container::fitting(std::fs::metadata(payload)?.len()),
+    };
+
+    let written = pack_inputs(&outer, &tname, payload).and_then(|inputs| match to {
+        _ if to.is_stdout() => {
+            archive::write(container, inputs, &mut std::io::stdout().lock()).map(|_| pname.clone())
+        }
+        // the archive is never written whole, so it only takes room once
+        Destination::Volumes(size) => {
+            let mut volumes = volume::Writer::new(Path::new(&pname), size);
+
+            match archive::write(container, inputs, &mut volumes).and_then(|_| volumes.finish()) {
+                Ok(m) => Result::Ok(m.to_string_lossy().to_string()),
+                Err(e) => volumes.abort().and(Result::Err(e)),
+            }
+        }
+        _ => File::create(&pname).and_then(|mut fd| {
+            archive::write(container, inputs, &mut fd)?;
+            fd.sync_all()?;
+
+            Result::Ok(pname.clone())
+        }),
+    });
+
+    std::fs::remove_file(tname)?;
+
+    if recipients.is_some() {
+        std::fs::remove_file(ename)?;
+    }
+
+    // volumes are removed by abort
+    if written.is_err() && !to.is_stdout() && !matches!(to, Destination::Volumes(_)) {
+        std::fs::remove_file(&pname)?;
+    }
+
+    written
+}
+
+/// the members of the archive of the file described by `info`, whose description
+/// is read from `sidecar` and data from `payload`
+fn pack_inputs<'a>(
+    info: &'a FileInfo,
+    sidecar: &str,
+    payload: &Path,
+) -> Result<Vec<archive::Input<'a>>, std::io::Error> {
+    Result::Ok(vec![
+        archive::Input {
+            name: &info.ncsum_name,
+            records: Vec::new(),
+            data: File::open(sidecar)?,
+        },
+        archive::Input {
+            name: &info.new_name,
+            records: info.pax_records(),
+            data: File::open(payload)?,
+        },
+    ])
+}
+
+/// packs a file with a .ncsum file describing it into a .pncsum archive, which replaces the file
+/// unless the archive is written to stdout
+fn pack_file(
+    file: &PathBuf,
+    block_size: Option<u64>,
+    recipients: Option<&crypt::Recipients>,
+    hmac_key: Option<&[u8]>,
+    format: format::Format,
+    container: Option<archive::Container>,
+    to: Destination,
+) -> Result<String, std::io::Error> {
+    let mut info = FileInfo::try_with_blocks(file, block_size)?;
+    let pname = write_pack(&mut info, file, recipients, hmac_key, format, container, to)?;
+
+    if !to.is_stdout() {
+        std::fs::remove_file(&info.old_name)?;
+    }
+
+    Result::Ok(pname)
+}
+
+/// packs a named file and its .ncsum file into a .pncsum archive, which replaces both
+/// unless the archive is written to stdout
+fn pack_sidecar(
+    sidecar: &PathBuf,
+    recipients: Option<&crypt::Recipients>,
+    hmac_key: Option<&[u8]>,
+    format: format::Format,
+    container: Option<archive::Container>,
+    to: Destination,
+) -> Result<String, std::io::Error> {
+    let mut info = FileInfo::load(sidecar)?;
+    let payload = PathBuf::from(&info.new_name);
+    let pname = write_pack(
+        &mut info, &payload, recipients, hmac_key, format, container, to,
+    )?;
+
+    if to.is_stdout() {
+        return Result::Ok(pname);
+    }
+
+    std::fs::remove_file(&info.ncsum_name)?;
+    std::fs::remove_file(&info.new_name)?;
+
+    Result::Ok(pname)
+}
+
+/// splits a .pncsum archive into volumes of `volume_size` when given, and returns
+/// the name of the archive or of the manifest of its volumes
+fn split_pack(pname: String, volume_size: Option<u64>) -> Result<String, std::io::Error> {
+    match volume_size {
+        Some(size) => {
+            let manifest = volume::split(Path::new(&pname), size)?;
+
+            Result::Ok(manifest.to_string_lossy().to_string())
+        }
+        None => Result::Ok(pname),
+    }
+}
+
+/// writes the file packed in a .pncsum archive to `to`, decrypting it with `keys`
+/// when it is encrypted, and returns its description once the written file matches its hash
+fn unpack(archive: &Path, to: &Path, keys: &crypt::Keys) -> Result<FileInfo, std::io::Error> {
+    verify_unpacked(to, write_payload(archive, to, keys))
+}
+
+/// checks the file `written` to `to` against its hash, removing it if it doesn't
+/// match or couldn't be written
+fn verify_unpacked(
+    to: &Path,
+    written: Result<FileInfo, std::io::Error>,
+) -> Result<FileInfo, std::io::Error> {
+    let written = written.and_then(|info| {
+        match legacy::hash(&mut File::open(to)?, &info)?.matches(&info.hash) {
+            true => Result::Ok(info),
+            false => Result::Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "An error occurred while unpacking the archive",
+            )),
+        }
+    });
+
+    if written.is_err() {
+        let _ = std::fs::remove_file(to);
+    }
+
+    written
+}
+
+fn write_payload(
+    archive: &Path,
+    to: &Path,
+    keys: &crypt::Keys,
+) -> Result<FileInfo, std::io::Error> {
+    let mut fd = volume::open(archive)?;
+    let read = read_payload(&mut fd, &mut File::create(to)?, keys);
+
+    // a missing or corrupt volume explains a failed read better than the read itself
+    fd.finish()?;
+
+    read?.ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{:?} does not contain a .ncsum file", archive),
+    ))
+}
+
+/// writes the file packed in the archive read from `input` to `out`, decrypting it
+/// with `keys` when it is encrypted, and returns the description found along with it
+fn read_payload(
+    input: impl Read,
+    out: &mut File,
+    keys: &crypt::Keys,
+) -> Result<Option<FileInfo>, std::io::Error> {
+    let mut info: Option<FileInfo> = None;
+
+    archive::read_members(input, |name, data| {
+        if name.ends_with(".ncsum") {
+            info = Some(format::read(data)?);
+        } else if archive::is_payload_name(name) {
+            // the encrypted data is an archive of the file and its original description
+            match info.as_ref().is_some_and(FileInfo::is_encrypted) {
+                true => info = read_payload(&mut keys.decrypt(data)? as &mut dyn Read, out, keys)?,
+                false => {
+                    std::io::copy(data, out)?;
+                }
+            };
+        }
+
+        Result::Ok(true)
+    })?;
+
+    out.sync_all()?;
+
+    Result::Ok(info)
+}
+
+/// the description of the file packed in a .pncsum archive, or split archive, which
+/// is read from inside the encrypted data with `keys` when the file is encrypted
+fn load_packed(archive: &Path, keys: &crypt::Keys) -> Result<FileInfo, std::io::Error> {
+    let info = FileInfo::load(&archive.to_path_buf())?;
+
+    if !info.is_encrypted() {
+        return Result::Ok(info);
+    }
+
+    let mut inner = None;
+
+    archive::read_members(volume::open(archive)?, |name, data| {
+        if archive::is_payload_name(name) {
+            archive::read_members(keys.decrypt(data)?, |name, data| {
+                if name.ends_with(".ncsum") {
+                    inner = Some(format::read(data)?);
+                }
+
+                Result::Ok(inner.is_none())
+            })?;
+        }
+
+        Result::Ok(inner.is_none())
+    })?;
+
+    inner.ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{:?} does not contain a .ncsum file", archive),
+    ))
+}
+
+/// writes the file packed in a .pncsum archive, or split archive, under its original
+/// name, or into `dir`, without touching the archive, and returns where it went
+fn extract(
+    archive: &Path,
+    dir: Option<&Path>,
+    keys: &crypt::Keys,
+) -> Result<PathBuf, std::io::Error> {
+    if !archive.to_string_lossy().ends_with(".pncsum") && !volume::is_manifest(archive) {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "not a .pncsum archive or .vncsum manifest",
+        ));
+    }
+
+    let info = load_packed(archive, keys)?;
+    let to = extract_to(&info, dir)?;
+    let tname = PathBuf::from(format!("{}.tncsum", to.to_string_lossy()));
+
+    unpack(archive, &tname, keys)?;
+    std::fs::rename(&tname, &to)?;
+
+    Result::Ok(to)
+}
+
+/// writes the file packed in the archive read from `input`, like `extract`
+///
+/// the archive is read once, and its description may come after the file, so the
+/// file is written under a temporary name in `dir` until its description is read
+fn extract_stream(
+    input: impl Read,
+    dir: Option<&Path>,
+    keys: &crypt::Keys,
+) -> Result<PathBuf, std::io::Error> {
+    let tname = dir
+        .unwrap_or(Path::new(""))
+        .join(format!("stdin-{}.tncsum", std::process::id()));
+
+    let written = File::create(&tname)
+        .and_then(|mut out| read_payload(input, &mut out, keys))
+        .and_then(|info| {
+            info.ok_or(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the archive does not contain a .ncsum file",
+            ))
+        });
+
+    let info = verify_unpacked(&tname, written)?;
+
+    let to = match extract_to(&info, dir) {
+        Ok(t) => t,
+        Err(e) => {
+            std::fs::remove_file(&tname)?;
+            return Result::Err(e);
+        }
+    };
+
+    std::fs::rename(&tname, &to)?;
+
+    Result::Ok(to)
+}
+
+/// where `extract` writes the file described by `info`, which mustn't exist yet
+fn extract_to(info: &FileInfo, dir: Option<&Path>) -> Result<PathBuf, std::io::Error> {
+    let to = match (dir, Path::new(&info.old_name).file_name()) {
+        (Some(d), Some(n)) => d.join(n),
+        _ => PathBuf::from(&info.old_name),
+    };
+
+    if to.exists() {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists", to),
+        ));
+    }
+
+    Result::Ok(to)
+}
+
+/// lists the files inside `dir`, and inside its subdirectories when `recursive` is set
+fn list_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            if recursive {
+                files.append(&mut list_files(&path, recursive)?);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Result::Ok(files)
+}
+
+/// whether a file is one of the files ncsum itself creates
+fn is_ncsum_file(name: &str) -> bool {
+    name.ends_with(".ncsum")
+        || name.ends_with(".pncsum")
+        || name.ends_with(".tncsum")
+        || name.ends_with(".rncsum")
+        || name.ends_with(".sncsum")
+        || name.ends_with(".vncsum")
+        || volume::is_volume(name)
+        || name.ends_with(index::INDEX_NAME)
+        || name.ends_with(state::STATE_NAME)
+        || name.ends_with(store::REFS_SUFFIX)
+}
+
+/// writes the header of the payload of a stream, before its data, with the
+/// placeholder name and size patched once the stream is read
+fn write_stream_header(
+    fd: &mut File,
+    container: archive::Container,
+    placeholder: &FileInfo,
+) -> Result<(), std::io::Error> {
+    match container {
+        archive::Container::Cpio => {
+            NewcBuilder::new(&placeholder.new_name)
+                .uid(1000)
+                .mode(0o100644)
+                .write(&mut *fd, 0)
+                .finish()?;
+        }
+        archive::Container::Tar => {
+            let records = placeholder.pax_records();
+
+            fd.write_all(&archive::tar_header(&placeholder.new_name, 0, &records)?)?;
+        }
+    };
+
+    Result::Ok(())
+}
+
+/// moves the data of a stream outgrowing the newc archive it was written to into
+/// a tar archive, which takes the place of the newc one
+fn restart_as_tar(
+    fd: File,
+    tname: &str,
+    data_offset: u64,
+    placeholder: &FileInfo,
+) -> Result<File, std::io::Error> {
+    let ttname = tname.replace(".tncsum", ".tar.tncsum");
+    let mut tar = File::create(&ttname)?;
+
+    write_stream_header(&mut tar, archive::Container::Tar, placeholder)?;
+
+    // the newc archive was opened for writing only
+    drop(fd);
+
+    let mut newc = File::open(tname)?;
+    newc.seek(SeekFrom::Start(data_offset))?;
+    std::io::copy(&mut newc, &mut tar)?;
+    std::fs::rename(&ttname, tname)?;
+
+    Result::Ok(tar)
+}
+
+/// packs a stream of unknown length into a .pncsum without a temporary copy of it
+///
+/// the payload is written first, under a placeholder name, while it is being hashed,
+/// then its name and size are patched into its header and the .ncsum entry is appended
+///
+/// without a `container`, the archive is cpio unless the stream outgrows it, its
+/// data being moved to a tar archive then
+///
+/// an archive written to stdout is only sent once complete, its headers needing the
+/// hash and size of the stream
+fn pack_stream(
+    input: &mut impl Read,
+    name: &PathBuf,
+    hmac_key: Option<&[u8]>,
+    format: format::Format,
+    container: Option<archive::Container>,
+    output: Option<&Path>,
+) -> Result<FileInfo, std::io::Error> {
+    // offsets of c_filesize and of the entry name inside a newc header
+    const FILESIZE_OFFSET: u64 = 54;
+    const NAME_OFFSET: u64 = 110;
+
+    let tname = String::from(name.to_str().expect("Error getting file name")) + ".tncsum";
+    let mut fd = File::create(&tname)?;
+
+    // every md5 digest has the same length, so the final name fits in its place
+    let placeholder = FileInfo::with_hash(name, "0".repeat(32), Algorithm::Md5);
+    let mut packed = container.unwrap_or_default();
+
+    write_stream_header(&mut fd, packed, &placeholder)?;
+
+    let data_offset = fd.stream_position()?;
+    let mut file_context = Hasher::new(Algorithm::Md5);
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut size: u64 = 0;
+
+    loop {
+        let len = input.read(&mut buffer)?;
+
+        if len == 0 {
+            break;
+        }
+
+        if packed == archive::Container::Cpio && size + len as u64 > archive::NEWC_MAX_SIZE {
+            if container.is_some() {
+                drop(fd);
+                std::fs::remove_file(&tname)?;
+
+                return Result::Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the stream is too large for a newc archive",
+                ));
+            }
+
+            fd = restart_as_tar(fd, &tname, data_offset, &placeholder)?;
+            packed = archive::Container::Tar;
+        }
+
+        file_context.consume(&buffer[..len]);
+        fd.write_all(&buffer[..len])?;
+        size += len as u64;
+    }
+
+    let mut info = FileInfo::with_hash(name, file_context.finish(), Algorithm::Md5);
+
+    if let Some(k) = hmac_key {
+        mac::seal(&mut info, k)?;
+    }
+
+    let encoded = format.encode(&info)?;
+
+    match packed {
+        archive::Container::Cpio => {
+            fd.write_all(&vec![0; (4 - size as usize % 4) % 4])?;
+
+            fd.seek(SeekFrom::Start(FILESIZE_OFFSET))?;
+            fd.write_all(format!("{:08x}", size).as_bytes())?;
+            fd.seek(SeekFrom::Start(NAME_OFFSET))?;
+            fd.write_all(info.new_name.as_bytes())?;
+            fd.seek(SeekFrom::End(0))?;
+
+            let mut writer = NewcBuilder::new(&info.ncsum_name)
+                .ino(1)
+                .uid(1000)
+                .mode(0o100644)
+                .write(&mut fd, encoded.len() as u32);
+
+            writer.write_all(&encoded)?;
+            writer.finish()?;
+            cpio::newc::trailer(&mut fd)?;
+        }
+        archive::Container::Tar => {
+            fd.write_all(&archive::tar_padding(size))?;
+
+            // the headers keep their length, the name and the size being as long as before
+            fd.seek(SeekFrom::Start(0))?;
+            fd.write_all(&archive::tar_header(
+                &info.new_name,
+                size,
+                &info.pax_records(),
+            )?)?;
+            fd.seek(SeekFrom::End(0))?;
+
+            fd.write_all(&archive::tar_header(
+                &info.ncsum_name,
+                encoded.len() as u64,
+                &[],
+            )?)?;
+            fd.write_all(&encoded)?;
+            fd.write_all(&archive::tar_padding(encoded.len() as u64))?;
+            fd.write_all(&archive::tar_end())?;
+        }
+    }
+
+    fd.flush()?;
+
+    match output {
+        Some(_) if is_stdout(output) => {
+            let mut stdout = std::io::stdout().lock();
+
+            std::io::copy(&mut File::open(&tname)?, &mut stdout)?;
+            stdout.flush()?;
+            std::fs::remove_file(&tname)?;
+        }
+        Some(o) => std::fs::rename(&tname, o)?,
+        None => std::fs::rename(&tname, info.ncsum_name.replace(".ncsum", ".pncsum"))?,
+    };
+
+    Result::Ok(info)
+}
+
+fn main() {
+    let args = Args::parse();
+
+    match args.command {
+        Commands::GetHash {
+            files,
+            check: false,
+            algorithm,
+            ..
+        } => {
+            let files = if files.is_empty() {
+                vec![PathBuf::from("-")]
+            } else {
+                files
+            };
+
+            for file in files {
+                let hash = if file.as_os_str() == "-" {
+                    get_hash(&mut std::io::stdin().lock(), algorithm.unwrap_or_default())
+                } else {
+                    match file.get_hash_with(algorithm.unwrap_or_default()) {
+                        Ok(h) => h,
+                        Err(e) => {
+                            println!("{e}");
+                            std::process::exit(1);
+                        }
+                    }
+                };
+
+                let entry = manifest::Entry {
+                    algorithm: algorithm.unwrap_or_default(),
+                    hash,
+                    name: file.to_string_lossy().to_string(),
+                };
+
+                println!("{}", manifest::format_entry(&entry, false));
+            }
+        }
+
+        Commands::GetHash {
+            files,
+            check: true,
+            algorithm,
+            quiet,
+            status,
+            ignore_missing,
+        } => {
+            let mut improper_lines = 0;
+            let mut unreadable = 0;
+            let mut mismatches = 0;
+            let mut verified = 0;
+
+            let files = if files.is_empty() {
+                vec![PathBuf::from("-")]
+            } else {
+                files
+            };
+
+            for file in files {
+                let content = if file.as_os_str() == "-" {
+                    std::io::read_to_string(std::io::stdin())
+                } else {
+                    std::fs::read_to_string(&file)
+                };
+
+                let content = match content {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("{}: {e}", file.to_string_lossy());
+                        std::process::exit(1);
+                    }
+                };
+
+                for line in content.lines() {
+                    let entry = match manifest::parse_line(line, algorithm) {
+                        Some(e) => e,
+                        None => {
+                            improper_lines += 1;
+                            continue;
+                        }
+                    };
+
+                    let name = manifest::display_name(&entry.name);
+
+                    // directories open fine, but fail to be read
+                    let hash = File::open(&entry.name)
+                        .and_then(|mut fd| try_get_hash(&mut fd, entry.algorithm));
+
+                    let hash = match hash {
+                        Ok(h) => h,
+                        Err(e) => {
+                            if ignore_missing && e.kind() == std::io::ErrorKind::NotFound {
+                                continue;
+                            }
+
+                            unreadable += 1;
+
+                            if !status {
+                                eprintln!("{name}: {e}");
+                                println!("{name}: FAILED open or read");
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    verified += 1;
+
+                    if hash == entry.hash {
+                        if !quiet && !status {
+                            println!("{name}: OK");
+                        }
+                    } else {
+                        mismatches += 1;
+
+                        if !status {
+                            println!("{name}: FAILED");
+                        }
+                    }
+                }
+            }
+
+            if !status {
+                if improper_lines > 0 {
+                    eprintln!("WARNING: {improper_lines} line(s) improperly formatted");
+                }
+
+                if unreadable > 0 {
+                    eprintln!("WARNING: {unreadable} listed file(s) could not be read");
+                }
+
+                if mismatches > 0 {
+                    eprintln!("WARNING: {mismatches} computed checksum(s) did NOT match");
+                }
+
+                if ignore_missing && verified == 0 {
+                    eprintln!("no file was verified");
+                }
+            }
+
+            if unreadable > 0 || mismatches > 0 || verified == 0 {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Name {
+            files,
+            block_size,
+            index: Some(index),
+            store,
+            hmac_key,
+            sidecar_format,
+        } => {
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+            let mut collection = match index::Index::load(&index) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            if let Some(f) = sidecar_format {
+                collection.format = f;
+            }
+
+            let mut infos = Vec::new();
+
+            for file in files {
+                if is_ncsum_file(&file.to_string_lossy()) {
+                    continue;
+                }
+
+                let mut info = FileInfo::with_blocks(&file, block_size);
+
+                if let Some(s) = &store {
+                    info.new_name = info.store_name(s);
+                }
+
+                if let Some(k) = &hmac_key {
+                    if let Err(e) = mac::seal(&mut info, k) {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                }
+
+                collection.add(info.clone());
+                infos.push(info);
+            }
+
+            // the records are saved before any file is renamed, so no file ever loses its original name
+            match collection.save() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {:?}", collection.path);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut failed = false;
+
+            for info in infos {
+                let renamed = match store {
+                    Some(_) => store::put(
+                        Path::new(&info.old_name),
+                        Path::new(&info.new_name),
+                        &info.hash,
+                        info.algorithm,
+                    ),
+                    None => {
+                        std::fs::rename(info.old_name.clone(), info.new_name.clone()).map(|_| true)
+                    }
+                };
+
+                match renamed {
+                    Ok(true) => println!("{:?} -> {:?}", info.old_name, info.new_name),
+                    Ok(false) => println!(
+                        "{:?} -> {:?} (already stored)",
+                        info.old_name, info.new_name
+                    ),
+                    Err(e) => {
+                        // the file stays where it was, so its record goes
+                        println!("{e}");
+                        collection.remove(&info);
+                        failed = true;
+                    }
+                };
+            }
+
+            if failed {
+                if let Err(e) = collection.save() {
+                    println!("{e}: {:?}", collection.path);
+                }
+
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Name {
+            files,
+            block_size,
+            index: None,
+            store,
+            hmac_key,
+            sidecar_format,
+        } => {
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+            for file in files {
+                if is_ncsum_file(&file.to_string_lossy()) {
+                    continue;
+                }
+
+                let (info, moved) = match name_file(
+                    &file,
+                    block_size,
+                    store.as_deref(),
+                    hmac_key.as_deref(),
+                    sidecar_format.unwrap_or_default(),
+                ) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("{e}: {file:?}");
+                        std::process::exit(1);
+                    }
+                };
+
+                match moved {
+                    true => println!("{:?} -> {:?}", info.old_name, info.new_name),
+                    false => println!(
+                        "{:?} -> {:?} (already stored)",
+                        info.old_name, info.new_name
+                    ),
+                }
+            }
+        }
+
+        Commands::Rename {
+            files,
+            index: Some(index),
+            keep,
+            ..
+        } => {
+            let mut collection = match index::Index::load(&index) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let mut failed = false;
+
+            for info in collection.select(&files) {
+                match store::take(
+                    Path::new(&info.new_name),
+                    &info.hash,
+                    Path::new(&info.old_name),
+                    keep,
+                ) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}");
+                        failed = true;
+                        break;
+                    }
+                };
+
+                collection.remove(&info);
+                println!("{:?} -> {:?}", info.new_name, info.old_name);
+            }
+
+            match collection.save() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {:?}", collection.path);
+                    std::process::exit(1);
+                }
+            };
+
+            if failed {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Rename {
+            files,
+            index: None,
+            keep,
+            identities,
+            passphrase_file,
+        } => {
+            let keys = match crypt::Keys::load(&identities, passphrase_file.as_deref()) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            for file in files {
+                let sfname = String::from(file.to_str().expect("Error getting file name"));
+                let mut fd: File;
+                let mut info = FileInfo {
+                    hash: String::new(),
+                    algorithm: Algorithm::Md5,
+                    old_name: String::new(),
+                    new_name: String::new(),
+                    ncsum_name: String::new(),
+                    blocks: None,
+                    encryption: None,
+                    hmac: None,
+                };
+
+                let mut old_name = String::new();
+
+                if sfname.ends_with(".ncsum") {
+                    fd = match File::open(sfname) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            println!("{e}");
+                            std::process::exit(1);
+                        }
+                    };
+
+                    info = match format::read(&mut fd) {
+                        Ok(j) => j,
+                        Err(e) => {
+                            println!("{e}");
+                            std::process::exit(1);
+                        }
+                    };
+
+                    old_name = info.old_name;
+                } else if sfname.ends_with(".pncsum") || volume::is_manifest(&file) {
+                    let tname = sfname
+                        .replace(".pncsum", ".tncsum")
+                        .replace(".vncsum", ".tncsum");
+
+                    info = match unpack(&file, Path::new(&tname), &keys) {
+                        Ok(i) => i,
+                        Err(e) => {
+                            println!("{e}");
+                            std::process::exit(1);
+                        }
+                    };
+
+                    old_name = info.old_name;
+                    info.new_name = tname;
+                    info.ncsum_name = sfname;
+                }
+
+                // unpacked files are never left behind
+                let keep = keep
+                    && !file.to_string_lossy().ends_with(".pncsum")
+                    && !volume::is_manifest(&file);
+
+                match store::take(
+                    Path::new(&info.new_name),
+                    &info.hash,
+                    Path::new(&old_name),
+                    keep,
+                ) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
+
+                let removed = match volume::is_manifest(&file) {
+                    true => volume::remove(&file),
+                    false => std::fs::remove_file(info.ncsum_name),
+                };
+
+                match removed {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
+
+                println!("{:?} -> {:?}", info.new_name, old_name);
+            }
+        }
+
+        Commands::Check {
+            files,
+            only_show_mismatches,
+            separate_mismatches,
+            quarantine_dir,
+            quarantine_mode,
+            detail,
+            index,
+            sample,
+            budget,
+            cursor,
+            older_than,
+            state,
+            identities,
+            passphrase_file,
+            require_signature,
+            public_keys,
+            hmac_key,
+        } => {
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+            let keys = match crypt::Keys::load(&identities, passphrase_file.as_deref()) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let trusted = match sign::load_public_keys(&public_keys) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let separate_mismatches =
+                separate_mismatches || quarantine_dir.is_some() || quarantine_mode.is_some();
+            let quarantine_mode = quarantine_mode.unwrap_or_default();
+
+            let mut collection = index.map(|i| match index::Index::load(&i) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+            // index records are checked directly, everything else through its sidecar
+            let mut targets: Vec<(PathBuf, Option<FileInfo>)> = match &collection {
+                Some(c) => c
+                    .select(&files)
+                    .into_iter()
+                    .map(|i| (c.path.clone(), Some(i)))
+                    .collect(),
+                None => files
+                    .into_iter()
+                    .filter(|f| f.to_string_lossy().ends_with("ncsum"))
+                    .map(|f| (f, None))
+                    .collect(),
+            };
+
+            // how files are known to the cursor and to the state
+            let key = |(file, info): &(PathBuf, Option<FileInfo>)| match info {
+                Some(i) => i.new_name.clone(),
+                None => file.to_string_lossy().to_string(),
+            };
+
+            // checks are only recorded when asked for
+            let mut state = match state.as_deref().map(state::State::load) {
+                Some(Ok(s)) => Some(s),
+                Some(Err(e)) => {
+                    println!("{e}: {state:?}");
+                    std::process::exit(1);
+                }
+                None => None,
+            };
+
+            let mut not_due = 0;
+
+            if let (Some(age), Some(state)) = (older_than, &state) {
+                let before = targets.len();
+
+                targets.retain(|t| state.is_due(&key(t), age));
+                not_due = before - targets.len();
+            }
+
+            // with a budget, files are checked in a fixed order starting after the last one checked
+            if budget.is_some() {
+                check::from_cursor(&mut targets, key, check::load_cursor(&cursor).as_deref());
+            }
+
+            let started = std::time::Instant::now();
+            let total = targets.len();
+            let mut last = None;
+            let mut not_sampled = 0;
+            let mut counts = HashMap::new();
+
+            for (n, target) in targets.into_iter().enumerate() {
+                if n > 0 && budget.is_some_and(|b| started.elapsed() >= b) {
+                    println!("budget spent, {} files left to check", total - n);
+                    break;
+                }
+
+                let name = key(&target);
+                let file_started = std::time::Instant::now();
+                last = Some(name.clone());
+
+                let (file, info) = target;
+                // a signature is checked before anything the description says is trusted
+                let rejected = match require_signature {
+                    true => check::verify_signature(&file, &trusted),
+                    false => None,
+                };
+                let rejected = match (rejected, &hmac_key) {
+                    (None, Some(k)) => check::verify_hmac(&file, info.as_ref(), k),
+                    (r, _) => r,
+                };
+
+                let report = match (rejected, info, sample) {
+                    (Some(r), _, _) => r,
+                    (None, info, Some(fraction)) => {
+                        match check::verify_sample(&file, info, fraction, &keys) {
+                            Some(r) => r,
+                            None => {
+                                not_sampled += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    (None, Some(i), None) => check::verify(i, detail),
+                    (None, None, None) => check::verify_file(&file, detail, &keys),
+                };
+
+                if let Some(state) = &mut state {
+                    match state.record(
+                        name.clone(),
+                        report.status,
+                        file_started.elapsed(),
+                        report.sampled.is_some(),
+                    ) {
+                        Ok(_) => (),
+                        Err(e) => {
+                            println!("{e}: {:?}", state.path);
+                            std::process::exit(1);
+                        }
+                    };
+                }
+
+                let status = match report.sampled {
+                    Some((n, total)) => format!("{} ({n}/{total} blocks sampled)", report.status),
+                    None if report.ciphertext_only => {
+                        format!("{} (encrypted data only)", report.status)
+                    }
+                    None => report.status.to_string(),
+                };
+
+                if report.status != check::Status::Ok || !only_show_mismatches {
+                    match &report.error {
+                        Some(e) => println!("{}: {status} ({e})", report.name(&file)),
+                        None => println!("{}: {status}", report.name(&file)),
+                    }
+                }
+
+                if let (Some(info), Some(actual)) = (&report.info, &report.blocks) {
+                    let expected = info
+                        .blocks
+                        .as_ref()
+                        .expect("blocks are only hashed when described");
+
+                    if actual.size != expected.size {
+                        println!(
+                            "  size is {} bytes, expected {}",
+                            actual.size, expected.size
+                        );
+                    }
+
+                    for (first, last) in report.ranges() {
+                        println!("  bytes {first}-{last} differ");
+                    }
+                }
+
+                *counts.entry(report.status).or_insert(0) += 1;
+
+                if report.status != check::Status::Mismatch || !separate_mismatches {
+                    continue;
+                }
+
+                let reason = report.reason();
+                let mut info = report.info.expect("mismatches always have a description");
+
+                // without a quarantine directory, files are put aside next to their description
+                let qdir = match &quarantine_dir {
+                    Some(d) => d.clone(),
+                    None => match &collection {
+                        Some(c) => c.path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                        None => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+                    },
+                };
+
+                // a file that can't be put aside is reported, the others are still checked
+                let mut manifest = match quarantine::Quarantine::load(&qdir) {
+                    Ok(q) => q,
+                    Err(e) => {
+                        println!("{}: not quarantined ({e}: {qdir:?})", info.old_name);
+                        continue;
+                    }
+                };
+
+                // packed files are put aside with their archive, or all of its volumes
+                let qfiles = if volume::is_manifest(&file) {
+                    match volume::files(&file) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            println!("{}: not quarantined ({e})", info.old_name);
+                            continue;
+                        }
+                    }
+                } else if file.to_string_lossy().ends_with(".pncsum") {
+                    vec![file.clone()]
+                } else if collection.is_some() {
+                    vec![PathBuf::from(&info.new_name)]
+                } else {
+                    vec![PathBuf::from(&info.new_name), file.clone()]
+                };
+
+                let index_path = collection.as_ref().map(|c| c.path.clone());
+                let record = match manifest.put(
+                    quarantine_mode,
+                    &info.hash.clone(),
+                    reason,
+                    &qfiles[..],
+                    index_path.as_deref(),
+                ) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!("{}: not quarantined ({e})", info.old_name);
+                        continue;
+                    }
+                };
+
+                match manifest.save() {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!(
+                            "{}: quarantined but not recorded ({e}: {:?})",
+                            info.old_name, manifest.dir
+                        );
+                        continue;
+                    }
+                };
+
+                if quarantine_mode != quarantine::Mode::ReportOnly {
+                    println!(
+                        "{}: quarantined in {:?}",
+                        info.old_name,
+                        manifest.dir.join(&info.hash)
+                    );
+                }
+
+                // the index has to point at the quarantined file for as long as it stays there
+                if let (Some(c), quarantine::Mode::Move) = (&mut collection, quarantine_mode) {
+                    c.remove(&info);
+                    let name = info.old_name.clone();
+                    info.new_name = record.files[0].to.clone();
+                    c.add(info);
+
+                    match c.save() {
+                        Ok(_) => (),
+                        Err(e) => println!("{name}: index not updated ({e}: {:?})", c.path),
+                    };
+                }
+            }
+
+            if budget.is_some() {
+                // a complete pass starts over from the beginning next time
+                let last = if counts.values().sum::<usize>() + not_sampled == total {
+                    None
+                } else {
+                    last
+                };
+
+                match check::save_cursor(&cursor, last.as_deref()) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}: {cursor:?}");
+                        std::process::exit(1);
+                    }
+                };
+            }
+
+            if let Some(state) = state.as_ref().filter(|s| !s.entries.is_empty()) {
+                match state.save() {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}: {:?}", state.path);
+                        std::process::exit(1);
+                    }
+                };
+            }
+
+            let mut summary: Vec<String> = check::Status::ALL
+                .iter()
+                .filter_map(|s| counts.get(s).map(|n| format!("{n} {s}")))
+                .collect();
+
+            if not_sampled > 0 {
+                summary.push(format!("{not_sampled} not sampled"));
+            }
+
+            if not_due > 0 {
+                summary.push(format!("{not_due} not due"));
+            }
+
+            if counts.values().sum::<usize>() + not_sampled + not_due > 1 {
+                println!("{}", summary.join(", "));
+            }
+
+            if counts.keys().any(|s| *s != check::Status::Ok) {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Status {
+            state,
+            within,
+            files,
+        } => {
+            let state = match state::State::load(&state) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("{e}: {state:?}");
+                    std::process::exit(1);
+                }
+            };
+
+            let mut names: Vec<String> = match files.is_empty() {
+                true => state.entries.keys().cloned().collect(),
+                false => files
+                    .iter()
+                    .map(|f| f.to_string_lossy().to_string())
+                    .collect(),
+            };
+            names.sort();
+
+            let now = state::now();
+            let (mut covered, mut never, mut failing) = (0, 0, 0);
+
+            for name in names.iter() {
+                let entry = match state.entries.get(name) {
+                    Some(e) => e,
+                    None => {
+                        println!("{name}: never checked");
+                        never += 1;
+                        continue;
+                    }
+                };
+
+                let mut line = format!(
+                    "{name}: {}{}, checked {} ago in {:.2}s",
+                    entry.status,
+                    if entry.sampled { " (sampled)" } else { "" },
+                    state::format_age(now.saturating_sub(entry.time)),
+                    entry.duration
+                );
+
+                match entry.last_verified() {
+                    Some(t) if t == entry.time => (),
+                    Some(t) => {
+                        line += &format!(
+                            ", verified {} ago",
+                            state::format_age(now.saturating_sub(t))
+                        )
+                    }
+                    None => line += ", never verified",
+                }
+
+                println!("{line}");
+
+                if entry.status != check::Status::Ok {
+                    failing += 1;
+                }
+
+                match entry.last_verified() {
+                    Some(t)
+                        if entry.status == check::Status::Ok
+                            && now.saturating_sub(t) < within.as_secs() =>
+                    {
+                        covered += 1
+                    }
+                    None => never += 1,
+                    _ => (),
+                }
+            }
+
+            println!(
+                "{} files, {covered} verified within {}, {never} never verified, {failing} failing",
+                names.len(),
+                state::format_age(within.as_secs())
+            );
+        }
+
+        Commands::Pack {
+            stdin: true,
+            name: Some(name),
+            hmac_key,
+            sidecar_format,
+            container,
+            volume_size,
+            output,
+            ..
+        } => {
+            // the archive itself goes to stdout with -o -, and messages to stderr
+            let say = |line: String| match is_stdout(output.as_deref()) {
+                true => eprintln!("{line}"),
+                false => println!("{line}"),
+            };
+
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    say(format!("{e}"));
+                    std::process::exit(1);
+                }
+            });
+
+            let packed = pack_stream(
+                &mut std::io::stdin().lock(),
+                &name,
+                hmac_key.as_deref(),
+                sidecar_format.unwrap_or_default(),
+                container,
+                output.as_deref(),
+            )
+            .and_then(|i| match &output {
+                Some(o) => Result::Ok(o.to_string_lossy().to_string()),
+                None => split_pack(i.ncsum_name.replace(".ncsum", ".pncsum"), volume_size),
+            });
+
+            match packed {
+                Ok(pname) => say(format!("{:?}: Created", pname)),
+                Err(e) => {
+                    say(format!("{e}"));
+                    std::process::exit(1);
+                }
+            };
+        }
+
+        Commands::Pack {
+            files,
+            block_size,
+            encrypt,
+            recipients,
+            passphrase_file,
+            hmac_key,
+            sidecar_format,
+            container,
+            volume_size,
+            output,
+            ..
+        } => {
+            // the archive itself goes to stdout with -o -, and messages to stderr
+            let say = |line: String| match is_stdout(output.as_deref()) {
+                true => eprintln!("{line}"),
+                false => println!("{line}"),
+            };
+
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    say(format!("{e}"));
+                    std::process::exit(1);
+                }
+            });
+
+            let recipients = match encrypt || !recipients.is_empty() {
+                true => match crypt::Recipients::load(recipients, passphrase_file.as_deref()) {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        say(format!("{e}"));
+                        std::process::exit(1);
+                    }
+                },
+                false => None,
+            };
+
+            if output.is_some() && files.len() != 1 {
+                say(String::from("--output takes a single FILE"));
+                std::process::exit(1);
+            }
+
+            let to = match (&output, volume_size) {
+                (Some(o), _) => Destination::Output(o),
+                (None, Some(size)) => Destination::Volumes(size),
+                (None, None) => Destination::Named,
+            };
+
+            for file in files {
+                let sfname = file.to_string_lossy();
+
+                let packed = if sfname.ends_with(".ncsum") {
+                    pack_sidecar(
+                        &file,
+                        recipients.as_ref(),
+                        hmac_key.as_deref(),
+                        sidecar_format.unwrap_or_default(),
+                        container,
+                        to,
+                    )
+                } else if !sfname.ends_with(".pncsum") {
+                    pack_file(
+                        &file,
+                        block_size,
+                        recipients.as_ref(),
+                        hmac_key.as_deref(),
+                        sidecar_format.unwrap_or_default(),
+                        container,
+                        to,
+                    )
+                } else {
+                    continue;
+                };
+
+                match packed {
+                    Ok(pname) => say(format!("{:?}: Created", pname)),
+                    Err(e) => {
+                        say(format!("{e}: {file:?}"));
+                        std::process::exit(1);
+                    }
+                };
+            }
+        }
+
+        Commands::ExportManifest {
+            files,
+            tag,
+            original_names,
+            output,
+        } => {
+            let mut out: Box<dyn Write> = match output {
+                Some(o) => match File::create(o) {
+                    Ok(fd) => Box::new(fd),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                },
+                None => Box::new(std::io::stdout()),
+            };
+
+            for file in files {
+                let sfname = file.to_string_lossy();
+
+                if !sfname.ends_with(".ncsum") && !sfname.ends_with(".pncsum") {
+                    continue;
+                }
+
+                let info = match FileInfo::load(&file) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("{}: {e}", sfname);
+                        std::process::exit(1);
+                    }
+                };
+
+                // the list is written to stdout by default, so it is told on stderr
+                if info.is_encrypted() {
+                    eprintln!(
+                        "{}: encrypted, the file can only be described once decrypted, skipped",
+                        sfname
+                    );
+                    continue;
+                }
+
+                // a file that can't be read is listed with its described hash
+                let hash = legacy::actual_hash(&file, &info).unwrap_or(info.hash.clone());
+
+                // the payload of a .pncsum only exists on disk after rename
+                let name = if original_names || sfname.ends_with(".pncsum") {
+                    info.old_name
+                } else {
+                    info.new_name
+                };
+
+                let entry = manifest::Entry {
+                    algorithm: info.algorithm,
+                    hash,
+                    name,
+                };
+
+                match writeln!(out, "{}", manifest::format_entry(&entry, tag)) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
+            }
+
+            match out.flush() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+        }
+
+        Commands::ImportManifest {
+            files,
+            algorithm,
+            rename,
+            verify,
+        } => {
+            for file in files {
+                let content = match std::fs::read_to_string(&file) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
+
+                for (n, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    let entry = match manifest::parse_line(line, algorithm) {
+                        Some(e) => e,
+                        None => {
+                            println!("{:?}: {}: improperly formatted checksum line", file, n + 1);
+                            continue;
+                        }
+                    };
+
+                    let path = PathBuf::from(&entry.name);
+
+                    if verify {
+                        match path.get_hash_with(entry.algorithm) {
+                            Ok(h) if h == entry.hash => (),
+                            Ok(_) => {
+                                println!("{}: The sum does not match, skipped", entry.name);
+                                continue;
+                            }
+                            Err(_) => continue,
+                        }
+                    } else if !path.is_file() {
+                        println!("{}: No such file, skipped", entry.name);
+                        continue;
+                    }
+
+                    let mut info = FileInfo::with_hash(&path, entry.hash, entry.algorithm);
+
+                    if !rename {
+                        info.new_name = info.old_name.clone();
+                    }
+
+                    match info.save() {
+                        Ok(_) => (),
+                        Err(e) => {
+                            println!("{e}: {:?}", info.ncsum_name);
                             std::process::exit(1);
                         }
                     };
 
-                    loop {
-                        let mut reader = match cpio::NewcReader::new(fd) {
-                            Ok(f) => f,
+                    if rename {
+                        match std::fs::rename(&info.old_name, &info.new_name) {
+                            Ok(_) => (),
                             Err(e) => {
                                 println!("{e}");
                                 std::process::exit(1);
                             }
                         };
 
-                        if reader.entry().is_trailer() {
-                            break;
-                        } else if reader.entry().name().ends_with(".ncsum") {
-                            info = match serde_json::from_reader(&mut reader) {
-                                Ok(i) => i,
-                                Err(e) => {
-                                    println!("{e}");
-                                    std::process::exit(1);
+                        println!("{:?} -> {:?}", info.old_name, info.new_name);
+                    } else {
+                        println!("{:?}: Created", info.ncsum_name);
+                    }
+                }
+            }
+        }
+
+        Commands::ToIndex { files, index } => {
+            let mut collection = match index::Index::load(&index) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let mut sidecars = Vec::new();
+
+            for file in files {
+                if !file.to_string_lossy().ends_with(".ncsum") {
+                    continue;
+                }
+
+                let info = match FileInfo::load(&file) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("{}: {e}", file.to_string_lossy());
+                        std::process::exit(1);
+                    }
+                };
+
+                collection.add(info);
+                sidecars.push(file);
+            }
+
+            match collection.save() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {:?}", collection.path);
+                    std::process::exit(1);
+                }
+            };
+
+            for file in sidecars {
+                match std::fs::remove_file(&file) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
+
+                println!("{:?} -> {:?}", file, collection.path);
+            }
+        }
+
+        Commands::FromIndex { files, index } => {
+            let mut collection = match index::Index::load(&index) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            for info in collection.select(&files) {
+                match info.save_as(collection.format) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{e}: {:?}", info.ncsum_name);
+                        std::process::exit(1);
+                    }
+                };
+
+                collection.remove(&info);
+                println!("{:?} -> {:?}", collection.path, info.ncsum_name);
+            }
+
+            match collection.save() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {:?}", collection.path);
+                    std::process::exit(1);
+                }
+            };
+        }
+
+        Commands::Audit {
+            dirs,
+            recursive,
+            fix,
+        } => {
+            let mut clean = true;
+
+            for dir in dirs {
+                clean &= audit::audit(&dir, recursive, fix);
+            }
+
+            if !clean {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Release {
+            quarantine_dir,
+            hashes,
+        } => {
+            let mut manifest = match quarantine::Quarantine::load(&quarantine_dir) {
+                Ok(q) => q,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let records: Vec<quarantine::Record> = manifest
+                .records
+                .iter()
+                .filter(|r| hashes.is_empty() || hashes.contains(&r.hash))
+                .cloned()
+                .collect();
+
+            let mut failed = false;
+
+            for record in records {
+                match manifest.release(&record) {
+                    Ok(_) => {
+                        for moved in record.files {
+                            match record.mode {
+                                quarantine::Mode::Move => {
+                                    println!("{:?} -> {:?}", moved.to, moved.from)
                                 }
-                            };
-                        } else {
-                            let mut buffer = [0; 1024 * 1024];
-
-                            loop {
-                                let len = match reader.read(&mut buffer) {
-                                    Ok(i) => i,
-                                    Err(e) => {
-                                        println!("{e}");
-                                        std::process::exit(1);
-                                    }
-                                };
-
-                                if len == 0 {
-                                    break;
+                                quarantine::Mode::Copy | quarantine::Mode::Hardlink => {
+                                    println!("{:?}: Removed", moved.to)
                                 }
-
-                                match out_fd.write_all(&buffer[..len]) {
-                                    Ok(_) => (),
-                                    Err(e) => {
-                                        println!("{e}");
-                                        std::process::exit(1);
-                                    }
-                                };
+                                quarantine::Mode::ReportOnly => (),
                             }
                         }
+                    }
+                    Err(e) => {
+                        println!("{}: {e}", record.hash);
+                        failed = true;
+                    }
+                };
+            }
 
-                        fd = match reader.finish() {
-                            Ok(f) => f,
-                            Err(e) => {
-                                println!("{e}");
-                                std::process::exit(1);
-                            }
-                        };
+            match manifest.save() {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {:?}", manifest.dir);
+                    std::process::exit(1);
+                }
+            };
+
+            if failed {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Protect {
+            files,
+            redundancy,
+            block_size,
+        } => {
+            for file in files {
+                match recovery::protect_file(&file, redundancy, block_size) {
+                    Ok(info) => println!("{}: Protected", info.old_name),
+                    Err(e) => {
+                        println!("{}: {e}", file.to_string_lossy());
+                        std::process::exit(1);
                     }
+                };
+            }
+        }
 
-                    match out_fd.flush() {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+        Commands::Repair { files } => {
+            let mut failed = false;
+
+            for file in files {
+                match recovery::repair_file(&file) {
+                    Ok((info, 0)) => {
+                        println!("{}: The sum matches, nothing to repair", info.old_name)
+                    }
+                    Ok((info, n)) => println!("{}: Repaired {n} block(s)", info.old_name),
+                    Err(e) => {
+                        println!("{}: {e}", file.to_string_lossy());
+                        failed = true;
+                    }
+                };
+            }
+
+            if failed {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Watch {
+            action,
+            stable,
+            block_size,
+            hmac_key,
+            sidecar_format,
+            dir,
+        } => {
+            let hmac_key = hmac_key.map(|k| match mac::load_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+            let options = watch::Options {
+                block_size,
+                hmac_key: hmac_key.as_deref(),
+                format: sidecar_format.unwrap_or_default(),
+            };
+
+            match watch::watch(&dir, action, stable, &options) {
+                Ok(_) => (),
+                Err(e) => {
+                    println!("{e}: {dir:?}");
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Dupes {
+            recursive,
+            link,
+            paths,
+        } => match dupes::dupes(&paths, recursive, link) {
+            Ok(_) => (),
+            Err(e) => {
+                println!("{e}");
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Diff {
+            recursive,
+            json,
+            a,
+            b,
+        } => {
+            // like diff, 1 tells the collections differ and 2 that they couldn't be compared
+            let (a, b) = match (diff::load(&a, recursive), diff::load(&b, recursive)) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(e), _) => {
+                    println!("{e}: {a:?}");
+                    std::process::exit(2);
+                }
+                (_, Err(e)) => {
+                    println!("{e}: {b:?}");
+                    std::process::exit(2);
+                }
+            };
+
+            let result = diff::diff(a, b);
+
+            if json {
+                match serde_json::to_string_pretty(&result) {
+                    Ok(j) => println!("{j}"),
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(2);
+                    }
+                };
+            } else {
+                for name in result.only_in_a.iter() {
+                    println!("only in A: {name:?}");
+                }
+
+                for name in result.only_in_b.iter() {
+                    println!("only in B: {name:?}");
+                }
+
+                for c in result.changed.iter() {
+                    println!("changed: {:?} ({} -> {})", c.name, c.a, c.b);
+                }
+
+                for m in result.moved.iter() {
+                    println!("moved: {:?} -> {:?} ({})", m.a, m.b, m.hash);
+                }
+
+                for name in result.incomparable.iter() {
+                    println!("hashed with different algorithms: {name:?}");
+                }
+
+                println!(
+                    "{} identical, {} only in A, {} only in B, {} changed, {} moved",
+                    result.identical,
+                    result.only_in_a.len(),
+                    result.only_in_b.len(),
+                    result.changed.len(),
+                    result.moved.len()
+                );
+            }
+
+            if !result.is_empty() {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Sync {
+            recursive,
+            delete,
+            dry_run,
+            src,
+            dst,
+        } => {
+            let summary = match sync::sync(&src, &dst, recursive, delete, dry_run) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            };
+
+            println!(
+                "{} copied, {} already there, {} deleted, {} failed",
+                summary.copied, summary.present, summary.deleted, summary.failed
+            );
 
-                    old_name = info.old_name;
-                    info.new_name = sfname.replace(".pncsum", ".tncsum");
-                    info.ncsum_name = sfname;
+            if summary.failed > 0 {
+                std::process::exit(1);
+            }
+        }
 
-                    let tinfo = FileInfo::new(&PathBuf::from(info.new_name.clone()));
+        Commands::Cp {
+            algorithm,
+            files,
+            dst,
+        } => {
+            if files.len() > 1 && !dst.is_dir() {
+                println!("{dst:?}: not a directory");
+                std::process::exit(1);
+            }
 
-                    if tinfo.hash != info.hash {
-                        println!("An error occurred while unpacking the archive");
-                        match std::fs::remove_file(tinfo.old_name) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                println!("{e}");
-                                std::process::exit(1);
-                            }
-                        };
+            let mut failed = false;
 
-                        std::process::exit(1);
+            for file in files {
+                match cp::cp(&file, &dst, algorithm) {
+                    Ok(to) => println!("{:?} -> {:?}", file, to),
+                    Err(e) => {
+                        println!("{:?}: FAILED ({e})", file);
+                        failed = true;
                     }
+                };
+            }
+
+            if failed {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Extract {
+            dir,
+            identities,
+            passphrase_file,
+            files,
+        } => {
+            let keys = match crypt::Keys::load(&identities, passphrase_file.as_deref()) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
                 }
+            };
 
-                match std::fs::rename(info.new_name.clone(), old_name.clone()) {
+            if let Some(d) = &dir {
+                match std::fs::create_dir_all(d) {
                     Ok(_) => (),
                     Err(e) => {
-                        println!("{e}");
+                        println!("{e}: {d:?}");
                         std::process::exit(1);
                     }
                 };
+            }
 
-                match std::fs::remove_file(info.ncsum_name) {
-                    Ok(_) => (),
+            let mut failed = false;
+
+            for file in files {
+                let extracted = match file.as_os_str() == "-" {
+                    true => extract_stream(std::io::stdin().lock(), dir.as_deref(), &keys),
+                    false => extract(&file, dir.as_deref(), &keys),
+                };
+
+                match extracted {
+                    Ok(to) => println!("{:?} -> {:?}", file, to),
                     Err(e) => {
-                        println!("{e}");
-                        std::process::exit(1);
+                        println!("{:?}: FAILED ({e})", file);
+                        failed = true;
                     }
                 };
+            }
 
-                println!("{:?} -> {:?}", info.new_name, old_name);
+            if failed {
+                std::process::exit(1);
             }
         }
 
-        Commands::Check {
+        Commands::Sign {
+            key,
+            generate,
             files,
-            only_show_mismatches,
-            separate_mismatches,
         } => {
-            for file in files {
-                let sfname = String::from(match file.to_str() {
-                    Some(n) => n,
-                    None => {
-                        println!("error getting {:?} name", file);
+            if generate {
+                match sign::generate_key(&key) {
+                    Ok(_) => println!("{:?}: Created", sign::public_name(&key)),
+                    Err(e) => {
+                        println!("{e}: {key:?}");
                         std::process::exit(1);
                     }
-                });
-
-                if !sfname.ends_with("ncsum") {
-                    continue;
-                }
-
-                let mut fd: File;
-                let mut info = FileInfo {
-                    hash: String::new(),
-                    old_name: String::new(),
-                    new_name: String::new(),
-                    ncsum_name: String::new(),
                 };
+            }
 
-                let mut new_hash = String::new();
-
-                if sfname.ends_with(".ncsum") {
-                    fd = match File::open(sfname.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    info = match serde_json::from_reader(fd) {
-                        Ok(i) => i,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    let mut new_fd = match File::open(info.new_name.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    new_hash = get_hash(&mut new_fd);
-                } else if sfname.ends_with(".pncsum") {
-                    fd = match File::open(sfname.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    loop {
-                        let mut reader = match cpio::NewcReader::new(fd) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                println!("{e}");
-                                std::process::exit(1);
-                            }
-                        };
-
-                        if reader.entry().is_trailer() {
-                            break;
-                        } else if reader.entry().name().ends_with(".ncsum") {
-                            info = match serde_json::from_reader(&mut reader) {
-                                Ok(i) => i,
-                                Err(e) => {
-                                    println!("{e}");
-                                    std::process::exit(1);
-                                }
-                            };
-                        } else {
-                            new_hash = get_hash(&mut reader);
-                        }
-
-                        fd = match reader.finish() {
-                            Ok(fd) => fd,
-                            Err(e) => {
-                                println!("{e}");
-                                std::process::exit(1);
-                            }
-                        };
-                    }
+            let key = match sign::load_signing_key(&key) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
                 }
+            };
 
-                if info.hash != new_hash {
-                    println!("{}: The sum does not match", info.old_name);
-                }
+            for file in files {
+                match sign::sign_file(&file, &key) {
+                    Ok(_) => println!("{:?}: Signed", file),
+                    Err(e) => {
+                        println!("{e}: {file:?}");
+                        std::process::exit(1);
+                    }
+                };
+            }
+        }
 
-                if !only_show_mismatches {
-                    println!("{}: The sum matches", info.old_name);
+        Commands::VerifySignature { public_keys, files } => {
+            let trusted = match sign::load_public_keys(&public_keys) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
                 }
+            };
 
-                if (info.hash != new_hash) && separate_mismatches {
-                    let sdir = file.parent().expect("").join(info.hash);
+            let mut failed = false;
 
-                    match std::fs::create_dir_all(&sdir) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
+            for file in files {
+                match sign::verify_file(&file, &trusted) {
+                    Ok(sign::Verdict::Valid) => println!("{:?}: {}", file, sign::Verdict::Valid),
+                    Ok(v) => {
+                        println!("{:?}: {v}", file);
+                        failed = true;
                     }
-
-                    let ofile = sdir.join(&info.new_name);
-                    let nfile: PathBuf = sdir.join(file.file_name().unwrap().to_str().unwrap());
-
-                    if sfname.ends_with(".ncsum") {
-                        match std::fs::rename(info.new_name, ofile) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                println!("{e}");
-                                std::process::exit(1);
-                            }
-                        }
+                    Err(e) => {
+                        println!("{:?}: {e}", file);
+                        failed = true;
                     }
+                };
+            }
 
-                    match std::fs::rename(file, nfile) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    }
-                }
+            if failed {
+                std::process::exit(1);
             }
         }
 
-        Commands::Pack { files } => {
+        Commands::List { files } => {
             for file in files {
-                let sfname = String::from(file.to_str().expect("Error getting file name"));
-                let fd: File;
-                let info: FileInfo;
-
-                if sfname.ends_with(".ncsum") {
-                    fd = match File::open(sfname) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    info = match serde_json::from_reader(fd) {
-                        Ok(i) => i,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    let pname = info.ncsum_name.replace(".ncsum", ".pncsum");
-                    let mut pcontent = vec![
-                        (
-                            NewcBuilder::new(info.ncsum_name.clone().as_str())
-                                .uid(1000)
-                                .mode(0o100644),
-                            match File::open(info.ncsum_name.clone()) {
-                                Ok(fd) => fd,
-                                Err(e) => {
-                                    println!("{e}");
-                                    std::process::exit(1);
-                                }
-                            },
-                        ),
-                        (
-                            NewcBuilder::new(info.new_name.clone().as_str())
-                                .uid(1000)
-                                .mode(0o100644),
-                            match File::open(info.new_name.clone()) {
-                                Ok(fd) => fd,
-                                Err(e) => {
-                                    println!("{e}");
-                                    std::process::exit(1);
-                                }
-                            },
-                        ),
-                    ];
-
-                    let pfile = match File::create(pname.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    match write_cpio(pcontent.drain(..), pfile) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
-
-                    match std::fs::remove_file(info.ncsum_name) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+                let listed = volume::open(&file).and_then(|mut fd| {
+                    Result::Ok((archive::Container::of(&mut fd)?, archive::members(&mut fd)?))
+                });
 
-                    match std::fs::remove_file(info.new_name) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+                let (container, members) = match listed {
+                    Ok(l) => l,
+                    Err(e) => {
+                        println!("{e}: {file:?}");
+                        std::process::exit(1);
+                    }
+                };
 
-                    println!("{:?}: Created", pname);
-                } else if !sfname.ends_with(".pncsum") {
-                    info = FileInfo::new(&file);
-                    let json = match serde_json::to_string(&info) {
-                        Ok(j) => j,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+                println!("{:?}: {container}", file);
 
-                    let pname = info.ncsum_name.replace(".ncsum", ".pncsum");
+                for member in members.iter().filter(|m| !m.is_trailer()) {
+                    println!("  {} ({} bytes)", member.name, member.size);
+                }
+            }
+        }
 
-                    let tname = info.ncsum_name.replace(".ncsum", ".tncsum");
-                    let mut tfile = match File::create(tname.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+        Commands::Migrate { index, key, files } => {
+            let key = key.map(|k| match sign::load_signing_key(&k) {
+                Ok(k) => k,
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            });
 
-                    match tfile.write_all(json.as_bytes()) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+            let mut failed = false;
 
-                    match tfile.flush() {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+            if let Some(i) = index {
+                let mut collection = match index::Index::load(&i) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        println!("{e}");
+                        std::process::exit(1);
+                    }
+                };
 
-                    let mut pcontent = vec![
-                        (
-                            NewcBuilder::new(info.ncsum_name.clone().as_str())
-                                .uid(1000)
-                                .mode(0o100644),
-                            match File::open(tname.clone()) {
-                                Ok(fd) => fd,
+                for info in collection.select(&files) {
+                    match legacy::migrate_record(&info) {
+                        Ok(Some(migrated)) => {
+                            collection.remove(&info);
+                            collection.add(migrated.clone());
+
+                            // saved at once, the file having been renamed already
+                            match collection.save() {
+                                Ok(_) => println!(
+                                    "{}: Migrated, renamed to {:?}",
+                                    migrated.old_name, migrated.new_name
+                                ),
                                 Err(e) => {
-                                    println!("{e}");
+                                    println!("{e}: {:?}", collection.path);
                                     std::process::exit(1);
                                 }
-                            },
-                        ),
-                        (
-                            NewcBuilder::new(info.new_name.clone().as_str())
-                                .uid(1000)
-                                .mode(0o100644),
-                            match File::open(info.old_name.clone()) {
-                                Ok(fd) => fd,
-                                Err(e) => {
-                                    println!("{e}");
-                                    std::process::exit(1);
-                                }
-                            },
-                        ),
-                    ];
-
-                    let pfile = match File::create(pname.clone()) {
-                        Ok(fd) => fd,
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
+                            };
                         }
-                    };
-
-                    match write_cpio(pcontent.drain(..), pfile) {
-                        Ok(_) => (),
+                        Ok(None) => println!("{}: Up to date", info.old_name),
                         Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
+                            println!("{}: {e}", info.old_name);
+                            failed = true;
                         }
                     };
+                }
+            } else {
+                for file in files {
+                    let sfname = file.to_string_lossy();
+
+                    if sfname.ends_with(".pncsum") || volume::is_manifest(&file) {
+                        println!(
+                            "{}: packed files keep their hash, repack them to update it",
+                            sfname
+                        );
+                        continue;
+                    }
 
-                    match std::fs::remove_file(info.old_name) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
-                        }
-                    };
+                    if !sfname.ends_with(".ncsum") {
+                        continue;
+                    }
 
-                    match std::fs::remove_file(tname) {
-                        Ok(_) => (),
+                    match legacy::migrate_sidecar(&file, key.as_ref()) {
+                        Ok(Some(migrated)) => println!(
+                            "{}: Migrated, renamed to {:?}",
+                            migrated.old_name, migrated.new_name
+                        ),
+                        Ok(None) => println!("{}: Up to date", sfname),
                         Err(e) => {
-                            println!("{e}");
-                            std::process::exit(1);
+                            println!("{}: {e}", sfname);
+                            failed = true;
                         }
                     };
-
-                    println!("{:?}: Created", pname);
                 }
             }
+
+            if failed {
+                std::process::exit(1);
+            }
         }
     }
 }
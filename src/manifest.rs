@@ -0,0 +1,235 @@
+//! reading and writing `md5sum`/`sha256sum` style checksum lists, both in the
+//! GNU (`HASH  NAME`) and in the BSD tagged (`MD5 (NAME) = HASH`) layout
+
+use crate::Algorithm;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub algorithm: Algorithm,
+    pub hash: String,
+    pub name: String,
+}
+
+/// coreutils prefixes a line with a backslash when the file name had to be escaped
+fn escape(name: &str) -> (bool, String) {
+    if !name.contains(['\\', '\n', '\r']) {
+        return (false, String::from(name));
+    }
+
+    let escaped = name
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+
+    (true, escaped)
+}
+
+/// file name as `md5sum -c` reports it, escaped and with a leading backslash if needed
+pub fn display_name(name: &str) -> String {
+    match escape(name) {
+        (true, escaped) => format!("\\{escaped}"),
+        (false, name) => name,
+    }
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next()? {
+            '\\' => out.push('\\'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            _ => return None,
+        }
+    }
+
+    Some(out)
+}
+
+fn is_hex(hash: &str) -> bool {
+    !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// formats an entry the way `md5sum` (or `md5sum --tag` when `tag` is set) prints it
+pub fn format_entry(entry: &Entry, tag: bool) -> String {
+    let (escaped, name) = escape(&entry.name);
+    let prefix = if escaped { "\\" } else { "" };
+
+    if tag {
+        format!(
+            "{prefix}{} ({name}) = {}",
+            entry.algorithm.tag(),
+            entry.hash
+        )
+    } else {
+        format!("{prefix}{}  {name}", entry.hash)
+    }
+}
+
+/// parses one line of a checksum list, returns `None` if the line is malformed
+///
+/// when `algorithm` is not given, GNU lines get it from the length of the digest
+pub fn parse_line(line: &str, algorithm: Option<Algorithm>) -> Option<Entry> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+
+    // the layout is told by how the line starts, as names may hold anything
+    let tagged = line
+        .split_once(" (")
+        .and_then(|(tag, rest)| Some((Algorithm::from_tag(tag)?, rest)));
+
+    let (algorithm, hash, name) = match tagged {
+        // BSD: "TAG (NAME) = HASH"
+        Some((algorithm, rest)) => {
+            let (name, hash) = rest.rsplit_once(") = ")?;
+
+            (algorithm, hash, name)
+        }
+        // GNU: "HASH  NAME" or "HASH *NAME"
+        None => {
+            let (hash, rest) = line.split_once(' ')?;
+            let name = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+            let algorithm = match algorithm {
+                Some(a) => a,
+                None => Algorithm::from_hex_len(hash.len())?,
+            };
+
+            (algorithm, hash, name)
+        }
+    };
+
+    if !is_hex(hash) || Algorithm::from_hex_len(hash.len()) != Some(algorithm) || name.is_empty() {
+        return None;
+    }
+
+    let name = if escaped {
+        unescape(name)?
+    } else {
+        String::from(name)
+    };
+
+    Some(Entry {
+        algorithm,
+        hash: hash.to_ascii_lowercase(),
+        name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5: &str = "b1946ac92492d2347c6235b4d2611184";
+
+    fn entry(algorithm: Algorithm, hash: &str, name: &str) -> Entry {
+        Entry {
+            algorithm,
+            hash: String::from(hash),
+            name: String::from(name),
+        }
+    }
+
+    fn parse(line: &str) -> (Algorithm, String, String) {
+        let e = parse_line(line, None).expect("the line parses");
+
+        (e.algorithm, e.hash, e.name)
+    }
+
+    #[test]
+    fn parses_gnu_lines() {
+        let name = String::from("a b.txt");
+
+        assert_eq!(
+            parse(&format!("{MD5}  a b.txt")),
+            (Algorithm::Md5, String::from(MD5), name.clone())
+        );
+        assert_eq!(
+            parse(&format!("{MD5} *a b.txt")),
+            (Algorithm::Md5, String::from(MD5), name)
+        );
+        assert_eq!(
+            parse(&format!("{}  x", "0".repeat(64))).0,
+            Algorithm::Sha256
+        );
+    }
+
+    #[test]
+    fn parses_bsd_lines() {
+        assert_eq!(
+            parse(&format!("MD5 (a (1).txt) = {MD5}")),
+            (Algorithm::Md5, String::from(MD5), String::from("a (1).txt"))
+        );
+        assert_eq!(
+            parse(&format!("SHA1 (x) = {}", "A".repeat(40))).1,
+            "a".repeat(40)
+        );
+    }
+
+    #[test]
+    fn tells_gnu_names_holding_a_tag() {
+        assert_eq!(
+            parse(&format!("{MD5}  MD5 (x) = {MD5}")).2,
+            format!("MD5 (x) = {MD5}")
+        );
+        assert_eq!(
+            parse(&format!("MD5 (a) = b) = {MD5}")).2,
+            String::from("a) = b")
+        );
+    }
+
+    #[test]
+    fn unescapes_names() {
+        assert_eq!(parse(&format!("\\{MD5}  a\\nb\\\\c\\r")).2, "a\nb\\c\r");
+        assert_eq!(parse(&format!("\\MD5 (a\\nb) = {MD5}")).2, "a\nb");
+        assert_eq!(parse(&format!("{MD5}  a\\nb")).2, "a\\nb");
+        assert!(parse_line(&format!("\\{MD5}  a\\tb"), None).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_line("", None).is_none());
+        assert!(parse_line(&format!("{MD5}  "), None).is_none());
+        assert!(parse_line("xyz  a", None).is_none());
+        assert!(parse_line(&format!("SHA1 (a) = {MD5}"), None).is_none());
+        assert!(parse_line(&format!("{MD5}  a"), Some(Algorithm::Sha1)).is_none());
+    }
+
+    #[test]
+    fn formats_entries() {
+        let plain = entry(Algorithm::Md5, MD5, "a b");
+        let escaped = entry(Algorithm::Sha1, &"0".repeat(40), "a\nb\\c\r");
+
+        assert_eq!(format_entry(&plain, false), format!("{MD5}  a b"));
+        assert_eq!(format_entry(&plain, true), format!("MD5 (a b) = {MD5}"));
+        assert_eq!(
+            format_entry(&escaped, false),
+            format!("\\{}  a\\nb\\\\c\\r", "0".repeat(40))
+        );
+        assert_eq!(
+            format_entry(&escaped, true),
+            format!("\\SHA1 (a\\nb\\\\c\\r) = {}", "0".repeat(40))
+        );
+        assert_eq!(display_name("a\nb"), "\\a\\nb");
+    }
+
+    #[test]
+    fn round_trips() {
+        for name in ["a", "a (1)", "a) = b", "a\nb", "a\\b", "\\"] {
+            for tag in [false, true] {
+                let line = format_entry(&entry(Algorithm::Md5, MD5, name), tag);
+
+                assert_eq!(parse(&line).2, name, "{line:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! puts files that failed a check aside, keeping a manifest of what was moved
+//! where and why, so `release` can put them back
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::index::Index;
+
+pub const MANIFEST_NAME: &str = "quarantine.ncsum-manifest";
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// move the files into the quarantine directory
+    #[default]
+    Move,
+    /// copy the files into the quarantine directory, leaving them in place
+    Copy,
+    /// hardlink the files into the quarantine directory, leaving them in place
+    Hardlink,
+    /// only record the files in the quarantine manifest
+    ReportOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Moved {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    /// the hash the files were expected to have
+    pub hash: String,
+    pub reason: String,
+    pub mode: Mode,
+    /// seconds since the unix epoch
+    pub time: u64,
+    /// collection index whose record was updated to the quarantined location
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    pub files: Vec<Moved>,
+}
+
+pub struct Quarantine {
+    pub dir: PathBuf,
+    pub records: Vec<Record>,
+}
+
+/// renames a file, falling back to copying it when it has to cross filesystems
+pub fn move_file(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        r => r,
+    }
+}
+
+impl Quarantine {
+    /// opens the quarantine manifest of `dir`, a missing one is treated as an empty one
+    pub fn load(dir: &Path) -> Result<Self, std::io::Error> {
+        let records = match File::open(dir.join(MANIFEST_NAME)) {
+            Ok(fd) => serde_json::from_reader(fd)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Result::Err(e),
+        };
+
+        Result::Ok(Self {
+            dir: dir.to_path_buf(),
+            records,
+        })
+    }
+
+    /// replaces the manifest on disk atomically, a manifest without records is removed
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let path = self.dir.join(MANIFEST_NAME);
+
+        if self.records.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Result::Err(e),
+                _ => Result::Ok(()),
+            };
+        }
+
+        let tname = self.dir.join(String::from(MANIFEST_NAME) + ".tncsum");
+        let mut fd = File::create(&tname)?;
+
+        fd.write_all(serde_json::to_string_pretty(&self.records)?.as_bytes())?;
+        fd.sync_all()?;
+        std::fs::rename(&tname, &path)
+    }
+
+    /// quarantines `files`, all of them belonging to the file expected to have `hash`,
+    /// under `<dir>/<hash>/`
+    ///
+    /// `index` is the collection index whose record the caller points to the moved file
+    ///
+    /// when one of the files can't be put aside, the ones that were are put back
+    pub fn put(
+        &mut self,
+        mode: Mode,
+        hash: &str,
+        reason: String,
+        files: &[PathBuf],
+        index: Option<&Path>,
+    ) -> Result<Record, std::io::Error> {
+        let sdir = self.dir.join(hash);
+        let mut moved: Vec<Moved> = Vec::new();
+
+        if mode != Mode::ReportOnly {
+            std::fs::create_dir_all(&sdir)?;
+        }
+
+        for file in files {
+            // files that are only reported stay where they are
+            let nfile = match mode {
+                Mode::ReportOnly => file.clone(),
+                _ => sdir.join(file.file_name().expect("Error getting file name")),
+            };
+
+            let put = match mode {
+                Mode::Move => move_file(file, &nfile),
+                Mode::Copy => std::fs::copy(file, &nfile).map(|_| ()),
+                Mode::Hardlink => std::fs::hard_link(file, &nfile),
+                Mode::ReportOnly => Result::Ok(()),
+            };
+
+            if let Err(e) = put {
+                for m in moved.iter() {
+                    let _ = match mode {
+                        Mode::Move => move_file(Path::new(&m.to), Path::new(&m.from)),
+                        _ => std::fs::remove_file(&m.to),
+                    };
+                }
+
+                let _ = std::fs::remove_dir(&sdir);
+
+                return Result::Err(std::io::Error::new(e.kind(), format!("{e}: {:?}", file)));
+            }
+
+            moved.push(Moved {
+                from: file.to_string_lossy().to_string(),
+                to: nfile.to_string_lossy().to_string(),
+            });
+        }
+
+        let record = Record {
+            hash: String::from(hash),
+            reason,
+            mode,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            index: index.map(|i| i.to_string_lossy().to_string()),
+            files: moved,
+        };
+
+        self.records.push(record.clone());
+
+        Result::Ok(record)
+    }
+
+    /// puts the files of a record back where they were, or drops the quarantined
+    /// copies when the originals were left in place
+    pub fn release(&mut self, record: &Record) -> Result<(), std::io::Error> {
+        for moved in record.files.iter() {
+            match record.mode {
+                Mode::Move => {
+                    if let Some(parent) = Path::new(&moved.from).parent() {
+                        if !parent.as_os_str().is_empty() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                    }
+
+                    move_file(Path::new(&moved.to), Path::new(&moved.from))?;
+                }
+                Mode::Copy | Mode::Hardlink => std::fs::remove_file(&moved.to)?,
+                Mode::ReportOnly => (),
+            }
+        }
+
+        if let (Mode::Move, Some(index)) = (record.mode, &record.index) {
+            let mut collection = Index::load(Path::new(index))?;
+
+            for moved in record.files.iter() {
+                for info in collection.files.iter_mut() {
+                    if info.new_name == moved.to {
+                        info.new_name = moved.from.clone();
+                    }
+                }
+            }
+
+            collection.save()?;
+        }
+
+        // the directory is only removed once nothing else is left in it
+        let _ = std::fs::remove_dir(self.dir.join(&record.hash));
+
+        if let Some(n) = self
+            .records
+            .iter()
+            .position(|r| r.time == record.time && r.hash == record.hash)
+        {
+            self.records.remove(n);
+        }
+
+        Result::Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+//! Reed-Solomon recovery data, able to rebuild damaged blocks of a file
+//!
+//! the file is split into blocks, and every stripe of up to `MAX_DATA_BLOCKS`
+//! consecutive blocks gets its own parity blocks, so a stripe can lose as many
+//! blocks as it has parity blocks. The hashes of every block tell which ones
+//! are damaged.
+//!
+//! recovery data is laid out as the parity blocks of every stripe, followed by
+//! the JSON `Recovery` description and its length as a little endian u64
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::legacy::{self, Hashed, Verifier};
+use crate::{archive, Algorithm, FileInfo};
+
+const MAX_DATA_BLOCKS: usize = 128;
+const MAX_BLOCKS: usize = 256;
+const MIN_BLOCK_SIZE: u64 = 4096;
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recovery {
+    /// hash of the whole file the recovery data was made for
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Algorithm::is_md5")]
+    pub algorithm: Algorithm,
+    pub size: u64,
+    pub block_size: u64,
+    /// data blocks of every stripe, the last one is padded with zeroed blocks
+    pub data_blocks: usize,
+    pub parity_blocks: usize,
+    /// md5 of every data block, zero padded to `block_size`
+    pub block_hashes: Vec<String>,
+    /// md5 of every parity block
+    pub parity_hashes: Vec<String>,
+}
+
+/// a region of a file, either a whole file or a member of a .pncsum archive
+pub struct Window {
+    pub fd: File,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Window {
+    pub fn new(fd: File, offset: u64, size: u64) -> Self {
+        Self { fd, offset, size }
+    }
+
+    /// a window over the whole file
+    pub fn whole(fd: File) -> Result<Self, std::io::Error> {
+        let size = fd.metadata()?.len();
+
+        Result::Ok(Self::new(fd, 0, size))
+    }
+
+    /// hashes the window for comparison with the description `info`
+    pub fn hash(&mut self, info: &FileInfo) -> Result<Hashed, std::io::Error> {
+        self.fd.seek(SeekFrom::Start(self.offset))?;
+
+        legacy::hash(&mut (&self.fd).take(self.size), info)
+    }
+
+    /// reads a block, zero padded to `block_size` even when the file is shorter than expected
+    fn read_block(&mut self, n: u64, block_size: u64) -> Result<Vec<u8>, std::io::Error> {
+        let mut block = vec![0; block_size as usize];
+        let start = n * block_size;
+
+        if start >= self.size {
+            return Result::Ok(block);
+        }
+
+        let len = block_size.min(self.size - start) as usize;
+        let mut read = 0;
+
+        self.fd.seek(SeekFrom::Start(self.offset + start))?;
+
+        while read < len {
+            match self.fd.read(&mut block[read..len])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        Result::Ok(block)
+    }
+
+    fn write_block(&mut self, n: u64, block_size: u64, block: &[u8]) -> Result<(), std::io::Error> {
+        let start = n * block_size;
+        let len = block_size.min(self.size - start) as usize;
+
+        self.fd.seek(SeekFrom::Start(self.offset + start))?;
+        self.fd.write_all(&block[..len])
+    }
+}
+
+fn block_hash(block: &[u8]) -> String {
+    format!("{:x}", md5::compute(block))
+}
+
+/// parses redundancies like `10%` or `10`, as a fraction of the file size
+pub fn parse_redundancy(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid redundancy {s:?}"))?;
+
+    if !(percent > 0.0 && percent <= 1000.0) {
+        return Result::Err(String::from("the redundancy must be between 0% and 1000%"));
+    }
+
+    Result::Ok(percent / 100.0)
+}
+
+/// chooses the block size and the number of data and parity blocks of every stripe
+fn layout(size: u64, redundancy: f64, block_size: Option<u64>) -> (u64, usize, usize) {
+    let parity = |data: usize| ((data as f64 * redundancy).ceil() as usize).max(1);
+
+    // a block larger than the file would only be zeros, and allocated for every shard
+    let block_size = match block_size {
+        Some(b) => b.clamp(1, MAX_BLOCK_SIZE.min(size.max(1))),
+        None => {
+            let target = size
+                .div_ceil(MAX_DATA_BLOCKS as u64)
+                .div_ceil(MIN_BLOCK_SIZE)
+                * MIN_BLOCK_SIZE;
+
+            target.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+        }
+    };
+
+    let blocks = (size.div_ceil(block_size) as usize).max(1);
+
+    let mut data_blocks = MAX_DATA_BLOCKS.min(blocks);
+
+    while data_blocks > 1 && data_blocks + parity(data_blocks) > MAX_BLOCKS {
+        data_blocks -= 1;
+    }
+
+    (
+        block_size,
+        data_blocks,
+        parity(data_blocks).min(MAX_BLOCKS - data_blocks),
+    )
+}
+
+/// writes the recovery data of `payload` to `out`
+///
+/// fails without protecting anything if the payload doesn't match its description `info`
+pub fn protect(
+    payload: &mut Window,
+    info: &FileInfo,
+    redundancy: f64,
+    block_size: Option<u64>,
+    out: &mut impl Write,
+) -> Result<Recovery, std::io::Error> {
+    let (block_size, data_blocks, parity_blocks) = layout(payload.size, redundancy, block_size);
+    let blocks = (payload.size.div_ceil(block_size)).max(1);
+    let stripes = blocks.div_ceil(data_blocks as u64);
+
+    let codec = ReedSolomon::new(data_blocks, parity_blocks)
+        .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+
+    let hash = &info.hash;
+    let mut file_context = Verifier::new(info);
+    let mut recovery = Recovery {
+        hash: String::from(hash),
+        algorithm: info.algorithm,
+        size: payload.size,
+        block_size,
+        data_blocks,
+        parity_blocks,
+        block_hashes: Vec::new(),
+        parity_hashes: Vec::new(),
+    };
+
+    for stripe in 0..stripes {
+        let mut shards = Vec::with_capacity(data_blocks + parity_blocks);
+
+        for n in 0..data_blocks as u64 {
+            let block_n = stripe * data_blocks as u64 + n;
+            let block = payload.read_block(block_n, block_size)?;
+
+            if block_n < blocks {
+                let start = block_n * block_size;
+                let len = block_size.min(payload.size.saturating_sub(start)) as usize;
+
+                file_context.consume(&block[..len]);
+                recovery.block_hashes.push(block_hash(&block));
+            }
+
+            shards.push(block);
+        }
+
+        shards.resize(data_blocks + parity_blocks, vec![0; block_size as usize]);
+
+        codec
+            .encode(&mut shards)
+            .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+
+        for parity in shards[data_blocks..].iter() {
+            recovery.parity_hashes.push(block_hash(parity));
+            out.write_all(parity)?;
+        }
+    }
+
+    if !file_context.finish().matches(hash) {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "The sum does not match, the file was not protected",
+        ));
+    }
+
+    let json = serde_json::to_vec(&recovery)?;
+
+    out.write_all(&json)?;
+    out.write_all(&(json.len() as u64).to_le_bytes())?;
+    out.flush()?;
+
+    Result::Ok(recovery)
+}
+
+/// reads the description at the end of some recovery data
+pub fn load(data: &mut Window) -> Result<Recovery, std::io::Error> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid recovery data");
+
+    if data.size < 8 {
+        return Result::Err(invalid());
+    }
+
+    let mut len = [0u8; 8];
+    data.fd.seek(SeekFrom::Start(data.offset + data.size - 8))?;
+    data.fd.read_exact(&mut len)?;
+
+    let len = u64::from_le_bytes(len);
+
+    if len > data.size - 8 {
+        return Result::Err(invalid());
+    }
+
+    let mut json = vec![0u8; len as usize];
+    data.fd
+        .seek(SeekFrom::Start(data.offset + data.size - 8 - len))?;
+    data.fd.read_exact(&mut json)?;
+
+    Result::Ok(serde_json::from_slice(&json)?)
+}
+
+/// replaces the hash of the file recovery data stored in `file` was made for
+pub fn rehash(file: &Path, hash: &str) -> Result<(), std::io::Error> {
+    let fd = OpenOptions::new().read(true).write(true).open(file)?;
+    let mut data = Window::whole(fd)?;
+    let mut recovery = load(&mut data)?;
+    let mut len = [0u8; 8];
+
+    // the description is replaced where it was, after the parity blocks
+    data.fd.seek(SeekFrom::Start(data.size - 8))?;
+    data.fd.read_exact(&mut len)?;
+
+    recovery.hash = String::from(hash);
+
+    let rehashed = serde_json::to_vec(&recovery)?;
+
+    data.fd.set_len(data.size - 8 - u64::from_le_bytes(len))?;
+    data.fd.seek(SeekFrom::End(0))?;
+    data.fd.write_all(&rehashed)?;
+    data.fd.write_all(&(rehashed.len() as u64).to_le_bytes())?;
+    data.fd.sync_all()
+}
+
+/// rebuilds the damaged blocks of `payload`
+///
+/// returns the number of rebuilt blocks and of stripes that had more damaged
+/// blocks than usable parity blocks
+pub fn repair(
+    payload: &mut Window,
+    data: &mut Window,
+    recovery: &Recovery,
+) -> Result<(usize, usize), std::io::Error> {
+    let block_size = recovery.block_size;
+    let blocks = recovery.block_hashes.len() as u64;
+    let stripes = blocks.div_ceil(recovery.data_blocks as u64);
+
+    let codec = ReedSolomon::new(recovery.data_blocks, recovery.parity_blocks)
+        .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+
+    let mut repaired = 0;
+    let mut unrecoverable = 0;
+
+    for stripe in 0..stripes {
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::new();
+        let mut damaged = Vec::new();
+
+        for n in 0..recovery.data_blocks as u64 {
+            let block_n = stripe * recovery.data_blocks as u64 + n;
+            let block = payload.read_block(block_n, block_size)?;
+
+            if block_n < blocks && block_hash(&block) != recovery.block_hashes[block_n as usize] {
+                damaged.push(n as usize);
+                shards.push(None);
+            } else {
+                shards.push(Some(block));
+            }
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+
+        let mut usable = 0;
+
+        for n in 0..recovery.parity_blocks as u64 {
+            let parity_n = stripe * recovery.parity_blocks as u64 + n;
+            let parity = data.read_block(parity_n, block_size)?;
+
+            if recovery.parity_hashes.get(parity_n as usize) == Some(&block_hash(&parity)) {
+                usable += 1;
+                shards.push(Some(parity));
+            } else {
+                shards.push(None);
+            }
+        }
+
+        if damaged.len() > usable {
+            unrecoverable += 1;
+            continue;
+        }
+
+        codec
+            .reconstruct_data(&mut shards)
+            .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+
+        for n in damaged {
+            let block_n = stripe * recovery.data_blocks as u64 + n as u64;
+            let block = shards[n].as_ref().expect("reconstructed data block");
+
+            payload.write_block(block_n, block_size, block)?;
+            repaired += 1;
+        }
+    }
+
+    payload.fd.flush()?;
+
+    Result::Ok((repaired, unrecoverable))
+}
+
+fn not_found(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, String::from(what))
+}
+
+/// where the recovery data of the file described by a .ncsum file is stored
+fn recovery_name(file: &Path) -> String {
+    file.to_string_lossy().replace(".ncsum", ".rncsum")
+}
+
+/// protects the file described by a .ncsum file, or packed in a .pncsum archive
+pub fn protect_file(
+    file: &Path,
+    redundancy: f64,
+    block_size: Option<u64>,
+) -> Result<FileInfo, std::io::Error> {
+    let info = FileInfo::load(&file.to_path_buf())?;
+    let sfname = file.to_string_lossy().to_string();
+    let tname = sfname.clone() + ".tncsum";
+
+    let mut payload = if sfname.ends_with(".pncsum") {
+        let mut fd = File::open(file)?;
+        let members = archive::members(&mut fd)?;
+
+        if members.iter().any(|m| m.name.ends_with(".rncsum")) {
+            return Result::Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "the archive already holds recovery data",
+            ));
+        }
+
+        let member = match members.iter().find(|m| m.is_payload()) {
+            Some(m) => m,
+            None => return Result::Err(not_found("the archive does not contain the file")),
+        };
+
+        Window::new(fd, member.data_offset, member.size)
+    } else {
+        Window::whole(File::open(&info.new_name)?)?
+    };
+
+    let mut out = File::create(&tname)?;
+
+    if let Err(e) = protect(&mut payload, &info, redundancy, block_size, &mut out) {
+        std::fs::remove_file(&tname)?;
+        return Result::Err(e);
+    }
+
+    out.sync_all()?;
+
+    if sfname.ends_with(".pncsum") {
+        let size = out.metadata()?.len();
+        let mut archive = OpenOptions::new().read(true).write(true).open(file)?;
+        let name = info.ncsum_name.replace(".ncsum", ".rncsum");
+
+        if let Err(e) = archive::append(&mut archive, &name, &mut File::open(&tname)?, size) {
+            std::fs::remove_file(&tname)?;
+
+            return Result::Err(e);
+        }
+
+        std::fs::remove_file(&tname)?;
+    } else {
+        std::fs::rename(&tname, recovery_name(file))?;
+    }
+
+    Result::Ok(info)
+}
+
+/// repairs the file described by a .ncsum file, or packed in a .pncsum archive
+///
+/// returns the number of rebuilt blocks, fails if the file couldn't be fully repaired
+pub fn repair_file(file: &Path) -> Result<(FileInfo, usize), std::io::Error> {
+    let info = FileInfo::load(&file.to_path_buf())?;
+
+    let (mut payload, mut data) = if file.to_string_lossy().ends_with(".pncsum") {
+        let mut fd = OpenOptions::new().read(true).write(true).open(file)?;
+        let members = archive::members(&mut fd)?;
+
+        let payload = match members.iter().find(|m| m.is_payload()) {
+            Some(m) => Window::new(fd.try_clone()?, m.data_offset, m.size),
+            None => return Result::Err(not_found("the archive does not contain the file")),
+        };
+
+        let data = match members.iter().find(|m| m.name.ends_with(".rncsum")) {
+            Some(m) => Window::new(fd, m.data_offset, m.size),
+            None => return Result::Err(not_found("the archive does not hold recovery data")),
+        };
+
+        (payload, data)
+    } else {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&info.new_name)?;
+        let data = Window::whole(File::open(recovery_name(file))?)?;
+
+        (Window::whole(fd)?, data)
+    };
+
+    if payload.hash(&info)?.matches(&info.hash) {
+        return Result::Ok((info, 0));
+    }
+
+    let recovery = load(&mut data)?;
+
+    if recovery.hash != info.hash {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the recovery data was made for another file",
+        ));
+    }
+
+    // a truncated or extended file gets back its original size
+    if file.to_string_lossy().ends_with(".ncsum") && payload.size != recovery.size {
+        payload.fd.set_len(recovery.size)?;
+        payload.size = recovery.size;
+    }
+
+    let (repaired, unrecoverable) = repair(&mut payload, &mut data, &recovery)?;
+
+    if unrecoverable > 0 || !payload.hash(&info)?.matches(&info.hash) {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "could not be repaired, {unrecoverable} stripe(s) have too many damaged blocks"
+            ),
+        ));
+    }
+
+    Result::Ok((info, repaired))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// a file of `size` bytes of varied data in a directory of its own, and its description
+    fn scratch(test: &str, size: usize) -> (PathBuf, FileInfo) {
+        let dir = std::env::temp_dir().join(format!("ncsum-{test}-{}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("file.bin");
+        let data: Vec<u8> = (0..size).map(|n| (n * 7 % 251) as u8).collect();
+        std::fs::write(&file, &data).unwrap();
+
+        let info = FileInfo::try_with_blocks(&file, None).unwrap();
+        std::fs::rename(&file, &info.new_name).unwrap();
+        info.save().unwrap();
+
+        (dir, info)
+    }
+
+    #[test]
+    fn caps_the_block_size() {
+        assert_eq!(layout(50_000, 0.1, Some(1 << 30)).0, 50_000);
+        assert_eq!(layout(50 << 20, 0.1, Some(1 << 30)).0, MAX_BLOCK_SIZE);
+        assert_eq!(layout(0, 0.1, Some(1 << 30)).0, 1);
+        assert_eq!(layout(50_000, 0.1, Some(0)).0, 1);
+    }
+
+    #[test]
+    fn repairs_what_it_protected() {
+        let (dir, info) = scratch("repair", 300_000);
+        let sidecar = PathBuf::from(&info.ncsum_name);
+
+        protect_file(&sidecar, 0.1, Some(1 << 30)).unwrap();
+        assert_eq!(repair_file(&sidecar).unwrap().1, 0);
+
+        let mut fd = OpenOptions::new().write(true).open(&info.new_name).unwrap();
+        fd.seek(SeekFrom::Start(1000)).unwrap();
+        fd.write_all(b"damaged").unwrap();
+        drop(fd);
+
+        // the whole file is a single block here, rebuilt from its parity block
+        assert_eq!(repair_file(&sidecar).unwrap().1, 1);
+        assert!(Window::whole(File::open(&info.new_name).unwrap())
+            .unwrap()
+            .hash(&info)
+            .unwrap()
+            .matches(&info.hash));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_damage_beyond_its_parity() {
+        let (dir, info) = scratch("unrecoverable", 300_000);
+        let sidecar = PathBuf::from(&info.ncsum_name);
+
+        protect_file(&sidecar, 0.1, Some(4096)).unwrap();
+
+        let mut fd = OpenOptions::new().write(true).open(&info.new_name).unwrap();
+        fd.write_all(&[0xff; 100_000]).unwrap();
+        drop(fd);
+
+        assert!(repair_file(&sidecar).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Ed25519 signatures over the description of a file, so a .ncsum file edited to
+//! match a tampered file no longer passes for the original
+//!
+//! the signature of a .ncsum file is stored next to it in a .sncsum file, and the
+//! one of a .pncsum archive in a .sncsum member, like recovery data. It covers the
+//! `FileInfo` serialized as compact JSON, with only the file names of its paths so
+//! signed files can be moved or copied, and for archives the name and size of
+//! every member but the recovery data and the signature itself
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{archive, FileInfo};
+
+/// prefix of every signed message, so signatures can't be taken for anything else
+const CONTEXT: &[u8] = b"ncsum signature v1\n";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signature {
+    /// the public key of the signer, in hex
+    pub key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Valid,
+    Unsigned,
+    /// a valid signature, by a key that isn't trusted
+    Untrusted(String),
+    /// a signature that doesn't match the description, or a malformed one
+    Forged,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Valid => f.write_str("signature OK"),
+            Verdict::Unsigned => f.write_str("UNSIGNED"),
+            Verdict::Untrusted(key) => write!(f, "UNTRUSTED KEY {key}"),
+            Verdict::Forged => f.write_str("BAD SIGNATURE"),
+        }
+    }
+}
+
+fn invalid(what: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+
+    for (n, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[n * 2..n * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// where the public key of a key file is written
+pub fn public_name(key: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.pub", key.to_string_lossy()))
+}
+
+/// creates a new secret key file, and its public key file next to it
+pub fn generate_key(key: &Path) -> Result<VerifyingKey, std::io::Error> {
+    let signing = SigningKey::from_bytes(&rand::random());
+    let public = signing.verifying_key();
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(key)?
+        .write_all(format!("{}\n", to_hex(signing.as_bytes())).as_bytes())?;
+    std::fs::write(public_name(key), format!("{}\n", to_hex(public.as_bytes())))?;
+
+    Result::Ok(public)
+}
+
+pub fn load_signing_key(key: &Path) -> Result<SigningKey, std::io::Error> {
+    match from_hex(&std::fs::read_to_string(key)?) {
+        Some(b) => Result::Ok(SigningKey::from_bytes(&b)),
+        None => Result::Err(invalid(format!("{:?} is not a secret key file", key))),
+    }
+}
+
+/// reads public key files, the public key of a secret key file being taken too
+pub fn load_public_keys(keys: &[PathBuf]) -> Result<Vec<VerifyingKey>, std::io::Error> {
+    let mut public = Vec::new();
+
+    for key in keys {
+        let bytes = match from_hex(&std::fs::read_to_string(key)?) {
+            Some(b) => b,
+            None => return Result::Err(invalid(format!("{:?} is not a key file", key))),
+        };
+
+        match key.to_string_lossy().ends_with(".pub") {
+            true => public.push(VerifyingKey::from_bytes(&bytes).map_err(invalid)?),
+            false => public.push(SigningKey::from_bytes(&bytes).verifying_key()),
+        }
+    }
+
+    Result::Ok(public)
+}
+
+/// the .sncsum file holding the signature of a .ncsum file
+pub fn signature_name(sidecar: &Path) -> PathBuf {
+    PathBuf::from(sidecar.to_string_lossy().replace(".ncsum", ".sncsum"))
+}
+
+fn is_packed(file: &Path) -> bool {
+    file.to_string_lossy().ends_with(".pncsum")
+}
+
+/// the signed members of an archive, and the signature member if there is one
+fn signed_members(
+    fd: &mut File,
+) -> Result<(Vec<archive::Member>, Option<archive::Member>), std::io::Error> {
+    let mut signed = Vec::new();
+    let mut signature = None;
+
+    for member in archive::members(fd)? {
+        if member.name.ends_with(".sncsum") {
+            signature = Some(member);
+        } else if !member.is_trailer() && !member.name.ends_with(".rncsum") {
+            signed.push(member);
+        }
+    }
+
+    Result::Ok((signed, signature))
+}
+
+/// what gets signed for a file described by `info`, inside an archive with the `members` given
+fn message(info: &FileInfo, members: &[archive::Member]) -> Result<Vec<u8>, std::io::Error> {
+    let mut message = CONTEXT.to_vec();
+    message.extend(serde_json::to_string(&info.with_file_names())?.as_bytes());
+
+    for member in members {
+        message.extend(format!("\n{} {}", member.name, member.size).as_bytes());
+    }
+
+    Result::Ok(message)
+}
+
+/// signs a .ncsum file or a .pncsum archive, a .ncsum file signed again gets its
+/// signature replaced
+pub fn sign_file(file: &Path, key: &SigningKey) -> Result<FileInfo, std::io::Error> {
+    let info = FileInfo::load(&file.to_path_buf())?;
+
+    if !is_packed(file) {
+        let signature = Signature {
+            key: to_hex(key.verifying_key().as_bytes()),
+            signature: to_hex(&key.sign(&message(&info, &[])?).to_bytes()),
+        };
+
+        let to = signature_name(file);
+        let tname = PathBuf::from(format!("{}.tncsum", to.to_string_lossy()));
+
+        std::fs::write(&tname, serde_json::to_string(&signature)?)?;
+        std::fs::rename(&tname, &to)?;
+
+        return Result::Ok(info);
+    }
+
+    let mut fd = OpenOptions::new().read(true).write(true).open(file)?;
+    let (members, signature) = signed_members(&mut fd)?;
+
+    if signature.is_some() {
+        return Result::Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "the archive is already signed",
+        ));
+    }
+
+    let signature = serde_json::to_string(&Signature {
+        key: to_hex(key.verifying_key().as_bytes()),
+        signature: to_hex(&key.sign(&message(&info, &members)?).to_bytes()),
+    })?;
+    let name = info.ncsum_name.replace(".ncsum", ".sncsum");
+
+    archive::append(
+        &mut fd,
+        &name,
+        &mut signature.as_bytes(),
+        signature.len() as u64,
+    )?;
+
+    Result::Ok(info)
+}
+
+/// checks the signature of a .ncsum file or a .pncsum archive against the `trusted` keys
+pub fn verify_file(file: &Path, trusted: &[VerifyingKey]) -> Result<Verdict, std::io::Error> {
+    let info = FileInfo::load(&file.to_path_buf())?;
+
+    let (signature, members) = if is_packed(file) {
+        let mut fd = File::open(file)?;
+        let (members, signature) = signed_members(&mut fd)?;
+
+        let signature = match signature {
+            Some(m) => {
+                let mut content = String::new();
+
+                fd.seek(SeekFrom::Start(m.data_offset))?;
+                (&fd).take(m.size).read_to_string(&mut content)?;
+
+                content
+            }
+            None => return Result::Ok(Verdict::Unsigned),
+        };
+
+        (signature, members)
+    } else {
+        match std::fs::read_to_string(signature_name(file)) {
+            Ok(s) => (s, Vec::new()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Result::Ok(Verdict::Unsigned)
+            }
+            Err(e) => return Result::Err(e),
+        }
+    };
+
+    let signature: Signature = match serde_json::from_str(&signature) {
+        Ok(s) => s,
+        Err(_) => return Result::Ok(Verdict::Forged),
+    };
+
+    let (key, bytes) = match (from_hex(&signature.key), from_hex(&signature.signature)) {
+        (Some(k), Some(s)) => (k, s),
+        _ => return Result::Ok(Verdict::Forged),
+    };
+
+    let key = match VerifyingKey::from_bytes(&key) {
+        Ok(k) => k,
+        Err(_) => return Result::Ok(Verdict::Forged),
+    };
+
+    let signed = message(&info, &members)?;
+
+    if key
+        .verify_strict(&signed, &ed25519_dalek::Signature::from_bytes(&bytes))
+        .is_err()
+    {
+        return Result::Ok(Verdict::Forged);
+    }
+
+    match trusted.contains(&key) {
+        true => Result::Ok(Verdict::Valid),
+        false => Result::Ok(Verdict::Untrusted(signature.key)),
+    }
+}
@@ -0,0 +1,159 @@
+//! a `scrub.ncsum-state` file recording when each file was last checked, how
+//! long it took and with what result, one JSON line per check
+//!
+//! checks are appended as they finish, so an interrupted check keeps what it did,
+//! and `save` compacts the file down to the last check of each file
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::check::Status;
+
+pub const STATE_NAME: &str = "scrub.ncsum-state";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// the checked .ncsum or .pncsum file, or hashed name for index records
+    pub name: String,
+    pub status: Status,
+    /// seconds since the unix epoch
+    pub time: u64,
+    /// seconds the check took
+    pub duration: f64,
+    /// whether only some blocks of the file were checked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sampled: bool,
+    /// last time every byte of the file was found to match, for checks that didn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified: Option<u64>,
+}
+
+pub struct State {
+    pub path: PathBuf,
+    pub entries: HashMap<String, Entry>,
+}
+
+/// seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// formats a number of seconds like `3d`, `5h`, `12m` or `40s`
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+impl Entry {
+    /// last time every byte of the file was found to match
+    pub fn last_verified(&self) -> Option<u64> {
+        match self.status {
+            Status::Ok if !self.sampled => Some(self.time),
+            _ => self.verified,
+        }
+    }
+}
+
+impl State {
+    /// opens the state at `path`, or the one inside `path` if it is a directory
+    ///
+    /// a missing state is treated as an empty one
+    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
+        let path = if path.is_dir() {
+            path.join(STATE_NAME)
+        } else {
+            path.to_path_buf()
+        };
+
+        let mut entries = HashMap::new();
+
+        let fd = match File::open(&path) {
+            Ok(fd) => fd,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Result::Ok(Self { path, entries })
+            }
+            Err(e) => return Result::Err(e),
+        };
+
+        for line in BufReader::new(fd).lines() {
+            let line = line?;
+
+            // a line cut short by an interrupted check is dropped
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                entries.insert(entry.name.clone(), entry);
+            }
+        }
+
+        Result::Ok(Self { path, entries })
+    }
+
+    /// records a check, appending it to the state on disk right away
+    pub fn record(
+        &mut self,
+        name: String,
+        status: Status,
+        duration: Duration,
+        sampled: bool,
+    ) -> Result<(), std::io::Error> {
+        let mut entry = Entry {
+            name: name.clone(),
+            status,
+            time: now(),
+            duration: duration.as_secs_f64(),
+            sampled,
+            verified: None,
+        };
+
+        entry.verified = self.entries.get(&name).and_then(Entry::last_verified);
+
+        let mut fd = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        fd.write_all(format!("{}\n", serde_json::to_string(&entry)?).as_bytes())?;
+        self.entries.insert(name, entry);
+
+        Result::Ok(())
+    }
+
+    /// whether a file failed its last check, or wasn't verified in full within the last `age`
+    pub fn is_due(&self, name: &str, age: Duration) -> bool {
+        match self.entries.get(name) {
+            Some(e) if e.status == Status::Ok => match e.last_verified() {
+                Some(t) => now().saturating_sub(t) >= age.as_secs(),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// replaces the state on disk with the last check of each file, through a
+    /// temporary file so it is never left half written
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let tname = PathBuf::from(format!("{}.tncsum", self.path.to_string_lossy()));
+        let mut fd = File::create(&tname)?;
+
+        for entry in entries {
+            fd.write_all(format!("{}\n", serde_json::to_string(entry)?).as_bytes())?;
+        }
+
+        fd.sync_all()?;
+        std::fs::rename(&tname, &self.path)
+    }
+}
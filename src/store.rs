@@ -0,0 +1,131 @@
+//! a content-addressed store, where named files live under `ab/cd/abcdef….ext`
+//! so no directory of the store ends up with too many entries
+//!
+//! every object has an `abcdef….ext.ncsum-refs` file next to it counting the
+//! descriptions that point at it, so it only leaves the store with the last one
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::quarantine::move_file;
+use crate::{try_get_hash, Algorithm};
+
+pub const REFS_SUFFIX: &str = ".ncsum-refs";
+
+/// where the file with `hash` and `suffix` lives inside `store`
+pub fn object_path(store: &Path, hash: &str, suffix: &str) -> PathBuf {
+    store
+        .join(&hash[..2])
+        .join(&hash[2..4])
+        .join(format!("{hash}{suffix}"))
+}
+
+/// whether `path` lies inside the shards of a store
+fn is_object(path: &Path, hash: &str) -> bool {
+    let shard = path.parent();
+    let top = shard.and_then(Path::parent);
+
+    hash.len() > 4
+        && shard.and_then(Path::file_name) == Some(hash[2..4].as_ref())
+        && top.and_then(Path::file_name) == Some(hash[..2].as_ref())
+}
+
+fn refs_path(object: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{REFS_SUFFIX}", object.to_string_lossy()))
+}
+
+/// how many descriptions point at `object`, unknown for objects stored before
+/// references were counted
+fn load_refs(object: &Path) -> Result<Option<u64>, std::io::Error> {
+    match std::fs::read_to_string(refs_path(object)) {
+        Ok(text) => match text.trim().parse() {
+            Ok(n) => Result::Ok(Some(n)),
+            Err(_) => Result::Ok(None),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Result::Ok(None),
+        Err(e) => Result::Err(e),
+    }
+}
+
+fn save_refs(object: &Path, refs: u64) -> Result<(), std::io::Error> {
+    std::fs::write(refs_path(object), refs.to_string())
+}
+
+/// moves `file`, with `hash`, into the store as `object`
+///
+/// returns false when the store already had the content, in which case `file` is
+/// removed instead, so the copies share the object. An object that no longer
+/// matches `hash` is left alone, and so is `file`, which may be the only good copy
+pub fn put(
+    file: &Path,
+    object: &Path,
+    hash: &str,
+    algorithm: Algorithm,
+) -> Result<bool, std::io::Error> {
+    if object.exists() {
+        if try_get_hash(&mut File::open(object)?, algorithm)? != hash {
+            return Result::Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the stored object {:?} doesn't match its hash, the file was kept",
+                    object
+                ),
+            ));
+        }
+
+        // an object stored before references were counted stays uncounted
+        if let Some(refs) = load_refs(object)? {
+            save_refs(object, refs + 1)?;
+        }
+
+        std::fs::remove_file(file)?;
+
+        return Result::Ok(false);
+    }
+
+    if let Some(shard) = object.parent() {
+        std::fs::create_dir_all(shard)?;
+    }
+
+    move_file(file, object)?;
+    save_refs(object, 1)?;
+
+    Result::Ok(true)
+}
+
+/// puts the file named `name` with `hash` back at `to`
+///
+/// objects of a store are copied while other descriptions still point at them, or
+/// when it isn't known whether any does, and always when `keep` is set
+pub fn take(name: &Path, hash: &str, to: &Path, keep: bool) -> Result<(), std::io::Error> {
+    if !is_object(name, hash) {
+        return match keep {
+            true => std::fs::copy(name, to).map(|_| ()),
+            false => move_file(name, to),
+        };
+    }
+
+    let refs = load_refs(name)?;
+
+    if keep || refs.is_none_or(|n| n > 1) {
+        std::fs::copy(name, to)?;
+
+        if let Some(n) = refs {
+            save_refs(name, n.saturating_sub(1))?;
+        }
+
+        return Result::Ok(());
+    }
+
+    move_file(name, to)?;
+    std::fs::remove_file(refs_path(name))?;
+
+    // shards are only removed once nothing else is left in them
+    let shard = name.parent().expect("objects always are inside shards");
+
+    if std::fs::remove_dir(shard).is_ok() {
+        let _ = std::fs::remove_dir(shard.parent().expect("shards always are inside a store"));
+    }
+
+    Result::Ok(())
+}
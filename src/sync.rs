@@ -0,0 +1,176 @@
+//! mirrors the files described by .ncsum files, or packed in .pncsum archives split
+//! into volumes or not, from one directory to another, copying only the content the destination is missing and verifying
+//! every copy against its hash before it takes its final name
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::cp;
+use crate::index::INDEX_NAME;
+use crate::sign::signature_name;
+use crate::volume;
+use crate::{list_files, FileInfo};
+
+/// a file described by a .ncsum file, or packed in a .pncsum archive
+struct Item {
+    /// the .ncsum file, the .pncsum archive or the .vncsum manifest of its volumes
+    path: PathBuf,
+    info: FileInfo,
+}
+
+impl Item {
+    fn is_packed(&self) -> bool {
+        self.path.to_string_lossy().ends_with(".pncsum") || volume::is_manifest(&self.path)
+    }
+
+    fn key(&self) -> (&'static str, String) {
+        (self.info.algorithm.tag(), self.info.hash.clone())
+    }
+
+    fn payload(&self) -> PathBuf {
+        cp::payload(&self.path, &self.info)
+    }
+
+    fn recovery(&self) -> PathBuf {
+        cp::recovery(&self.path)
+    }
+
+    fn signature(&self) -> PathBuf {
+        signature_name(&self.path)
+    }
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub copied: usize,
+    pub present: usize,
+    pub deleted: usize,
+    pub failed: usize,
+}
+
+fn items(dir: &Path, recursive: bool, summary: &mut Summary) -> Result<Vec<Item>, std::io::Error> {
+    let mut items = Vec::new();
+
+    for file in list_files(dir, recursive)? {
+        let sfname = file.to_string_lossy();
+
+        if sfname.ends_with(INDEX_NAME) {
+            println!("{:?}: collection indexes are not synced", file);
+            continue;
+        }
+
+        if !sfname.ends_with(".ncsum")
+            && !sfname.ends_with(".pncsum")
+            && !volume::is_manifest(&file)
+        {
+            continue;
+        }
+
+        match FileInfo::load(&file) {
+            Ok(info) => items.push(Item { path: file, info }),
+            Err(e) => {
+                println!("{:?}: {e}", file);
+                summary.failed += 1;
+            }
+        };
+    }
+
+    Result::Ok(items)
+}
+
+fn delete(item: &Item) -> Result<(), std::io::Error> {
+    if volume::is_manifest(&item.path) {
+        return volume::remove(&item.path);
+    }
+
+    if !item.is_packed() {
+        std::fs::remove_file(item.payload())?;
+
+        for companion in [item.recovery(), item.signature()] {
+            if companion.exists() {
+                std::fs::remove_file(companion)?;
+            }
+        }
+    }
+
+    std::fs::remove_file(&item.path)
+}
+
+/// copies the files of `src` whose content `dst` doesn't have yet, keeping their
+/// place in the tree, and removes from `dst` the content `src` doesn't have when
+/// `delete_missing` is set
+pub fn sync(
+    src: &Path,
+    dst: &Path,
+    recursive: bool,
+    delete_missing: bool,
+    dry_run: bool,
+) -> Result<Summary, std::io::Error> {
+    let mut summary = Summary::default();
+
+    let sources = items(src, recursive, &mut summary)?;
+    let targets = match dst.exists() {
+        true => items(dst, recursive, &mut summary)?,
+        false => Vec::new(),
+    };
+
+    let mut present: HashSet<_> = targets.iter().map(Item::key).collect();
+
+    for item in sources.iter() {
+        if present.contains(&item.key()) {
+            summary.present += 1;
+            continue;
+        }
+
+        let relative = item
+            .path
+            .parent()
+            .and_then(|p| p.strip_prefix(src).ok())
+            .unwrap_or(Path::new(""));
+        let dir = dst.join(relative);
+
+        if dry_run {
+            println!("{:?} -> {:?}", item.path, dir);
+            summary.copied += 1;
+            continue;
+        }
+
+        match cp::copy_into(&item.path, &item.info, &dir) {
+            Ok(to) => {
+                println!("{:?} -> {:?}", item.path, to);
+                present.insert(item.key());
+                summary.copied += 1;
+            }
+            Err(e) => {
+                println!("{:?}: {e}", item.path);
+                summary.failed += 1;
+            }
+        };
+    }
+
+    if !delete_missing {
+        return Result::Ok(summary);
+    }
+
+    let wanted: HashSet<_> = sources.iter().map(Item::key).collect();
+
+    for item in targets.iter().filter(|i| !wanted.contains(&i.key())) {
+        let deleted = match dry_run {
+            true => Result::Ok(()),
+            false => delete(item),
+        };
+
+        match deleted {
+            Ok(_) => {
+                println!("{:?}: deleted", item.path);
+                summary.deleted += 1;
+            }
+            Err(e) => {
+                println!("{:?}: {e}", item.path);
+                summary.failed += 1;
+            }
+        };
+    }
+
+    Result::Ok(summary)
+}
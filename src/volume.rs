@@ -0,0 +1,676 @@
+//! .pncsum archives split into fixed-size volumes, `<hash>.pncsum.001`, `.002` and
+//! so on, described by a `<hash>.vncsum` manifest holding the hash of every volume
+//!
+//! volumes are put back together on the fly while reading, each one being checked
+//! against its hash, so that a missing or corrupt volume is told by name
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Algorithm, Hasher};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Volume {
+    /// file name of the volume, next to the manifest
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    /// file name of the archive the volumes make up
+    pub archive: String,
+    #[serde(default, skip_serializing_if = "Algorithm::is_md5")]
+    pub algorithm: Algorithm,
+    pub volume_size: u64,
+    pub volumes: Vec<Volume>,
+}
+
+/// whether a file is the manifest of a split archive
+pub fn is_manifest(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".vncsum")
+}
+
+/// whether a file is a volume of a split archive, like `<hash>.pncsum.001`
+pub fn is_volume(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((stem, n)) => {
+            stem.ends_with(".pncsum") && !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+fn missing(path: &Path) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("volume {:?} is missing", path))
+}
+
+fn corrupt(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("volume {:?} is corrupt", path),
+    )
+}
+
+/// copies `len` bytes of `from`, into `to` when given, and hashes them
+fn copy_hashed(
+    from: &mut impl Read,
+    mut to: Option<&mut File>,
+    hasher: &mut Hasher,
+    len: u64,
+) -> Result<u64, std::io::Error> {
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut from = from.take(len);
+    let mut copied = 0;
+
+    loop {
+        let s = from.read(&mut buffer)?;
+
+        if s == 0 {
+            return Result::Ok(copied);
+        }
+
+        hasher.consume(&buffer[..s]);
+        copied += s as u64;
+
+        if let Some(t) = to.as_mut() {
+            t.write_all(&buffer[..s])?;
+        }
+    }
+}
+
+/// the name of volume `n`, counted from 0, of the archive named `archive`
+fn volume_name(archive: &str, n: usize) -> String {
+    format!("{archive}.{:03}", n + 1)
+}
+
+/// the manifest of the volumes of the archive at `archive`, before any is written
+fn new_manifest(archive: &Path, volume_size: u64) -> Manifest {
+    Manifest {
+        archive: archive
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(archive.to_string_lossy().to_string()),
+        algorithm: Algorithm::Md5,
+        volume_size,
+        volumes: Vec::new(),
+    }
+}
+
+/// writes the manifest of the volumes of `archive`, returning where it is
+fn save_manifest(archive: &Path, manifest: &Manifest) -> Result<PathBuf, std::io::Error> {
+    let sname = archive.to_string_lossy().to_string();
+    let mname = PathBuf::from(sname.replace(".pncsum", ".vncsum"));
+    let tname = PathBuf::from(sname.replace(".pncsum", ".vncsum.tncsum"));
+    let mut tfile = File::create(&tname)?;
+
+    tfile.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+    tfile.sync_all()?;
+    std::fs::rename(&tname, &mname)?;
+
+    Result::Ok(mname)
+}
+
+/// splits `archive` into volumes of `volume_size` bytes, the last one being shorter,
+/// writes their manifest and removes the archive, returning where the manifest is
+///
+/// volumes are cut from the end of the archive, which is shortened after each one,
+/// so that splitting only needs room for a single volume. When it fails, the volumes
+/// already cut are put back
+pub fn split(archive: &Path, volume_size: u64) -> Result<PathBuf, std::io::Error> {
+    let dir = archive.parent().unwrap_or(Path::new(""));
+    let mut manifest = new_manifest(archive, volume_size);
+
+    let mut fd = OpenOptions::new().read(true).write(true).open(archive)?;
+    let size = fd.metadata()?.len();
+    let count = size.div_ceil(volume_size).max(1) as usize;
+
+    let mut cut = || -> Result<(), std::io::Error> {
+        for n in (0..count).rev() {
+            let name = volume_name(&manifest.archive, n);
+            let start = n as u64 * volume_size;
+            let mut hasher = Hasher::new(manifest.algorithm);
+
+            fd.seek(SeekFrom::Start(start))?;
+
+            // the first volume is what is left of the archive
+            let len = match n {
+                0 => copy_hashed(&mut fd, None, &mut hasher, volume_size)?,
+                _ => {
+                    let path = dir.join(&name);
+                    let mut out = File::create(&path)?;
+
+                    // the archive is only shortened once the volume is on disk
+                    let cut = copy_hashed(&mut fd, Some(&mut out), &mut hasher, volume_size)
+                        .and_then(|len| out.sync_all().map(|_| len))
+                        .and_then(|len| fd.set_len(start).map(|_| len));
+
+                    match cut {
+                        Ok(len) => len,
+                        Err(e) => {
+                            let _ = std::fs::remove_file(&path);
+                            return Result::Err(e);
+                        }
+                    }
+                }
+            };
+
+            manifest.volumes.insert(
+                0,
+                Volume {
+                    name,
+                    size: len,
+                    hash: hasher.finish(),
+                },
+            );
+        }
+
+        fd.sync_all()?;
+        std::fs::rename(archive, dir.join(&manifest.volumes[0].name))
+    };
+
+    if let Err(e) = cut() {
+        rejoin(archive, dir, &manifest)?;
+        return Result::Err(e);
+    }
+
+    save_manifest(archive, &manifest)
+}
+
+/// appends the volumes cut from the end of `archive` back to it, in order
+fn rejoin(archive: &Path, dir: &Path, manifest: &Manifest) -> Result<(), std::io::Error> {
+    let mut fd = OpenOptions::new().append(true).open(archive)?;
+    let first = volume_name(&manifest.archive, 0);
+
+    // the first volume is the archive itself until it is renamed
+    for volume in manifest.volumes.iter().filter(|v| v.name != first) {
+        let path = dir.join(&volume.name);
+
+        std::io::copy(&mut File::open(&path)?, &mut fd)?;
+        fd.sync_all()?;
+        std::fs::remove_file(&path)?;
+    }
+
+    Result::Ok(())
+}
+
+/// writes an archive straight into volumes of `volume_size` bytes, named after
+/// `archive`, which is never written whole
+pub struct Writer {
+    archive: PathBuf,
+    dir: PathBuf,
+    manifest: Manifest,
+    /// the volume being written, its hash so far and its length
+    current: Option<(File, Hasher, u64)>,
+}
+
+impl Writer {
+    pub fn new(archive: &Path, volume_size: u64) -> Self {
+        Self {
+            archive: archive.to_path_buf(),
+            dir: archive.parent().unwrap_or(Path::new("")).to_path_buf(),
+            manifest: new_manifest(archive, volume_size),
+            current: None,
+        }
+    }
+
+    /// closes the volume being written, adding it to the manifest
+    fn close(&mut self) -> Result<(), std::io::Error> {
+        if let Some((fd, hasher, len)) = self.current.take() {
+            fd.sync_all()?;
+
+            self.manifest.volumes.push(Volume {
+                name: volume_name(&self.manifest.archive, self.manifest.volumes.len()),
+                size: len,
+                hash: hasher.finish(),
+            });
+        }
+
+        Result::Ok(())
+    }
+
+    /// closes the last volume and writes the manifest, returning where it is
+    pub fn finish(&mut self) -> Result<PathBuf, std::io::Error> {
+        // an empty archive still has a volume
+        if self.current.is_none() && self.manifest.volumes.is_empty() {
+            let fd = File::create(self.dir.join(volume_name(&self.manifest.archive, 0)))?;
+
+            self.current = Some((fd, Hasher::new(self.manifest.algorithm), 0));
+        }
+
+        self.close()?;
+
+        save_manifest(&self.archive, &self.manifest)
+    }
+
+    /// removes the volumes written so far
+    pub fn abort(&mut self) -> Result<(), std::io::Error> {
+        let written = self.manifest.volumes.len() + usize::from(self.current.is_some());
+        self.current = None;
+
+        for n in 0..written {
+            match std::fs::remove_file(self.dir.join(volume_name(&self.manifest.archive, n))) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Result::Err(e),
+                _ => (),
+            };
+        }
+
+        Result::Ok(())
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let volume_size = self.manifest.volume_size;
+
+        if buf.is_empty() {
+            return Result::Ok(0);
+        }
+
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(_, _, len)| *len == volume_size)
+        {
+            self.close()?;
+        }
+
+        let (fd, hasher, len) = match &mut self.current {
+            Some(c) => c,
+            None => {
+                let name = volume_name(&self.manifest.archive, self.manifest.volumes.len());
+                let fd = File::create(self.dir.join(name))?;
+
+                self.current
+                    .insert((fd, Hasher::new(self.manifest.algorithm), 0))
+            }
+        };
+
+        let n = buf
+            .len()
+            .min(usize::try_from(volume_size - *len).unwrap_or(usize::MAX));
+
+        fd.write_all(&buf[..n])?;
+        hasher.consume(&buf[..n]);
+        *len += n as u64;
+
+        Result::Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some((fd, _, _)) => fd.flush(),
+            None => Result::Ok(()),
+        }
+    }
+}
+
+/// removes the volumes of a split archive, then its manifest
+pub fn remove(manifest: &Path) -> Result<(), std::io::Error> {
+    let volumes = Volumes::open(manifest)?;
+
+    for volume in volumes.manifest.volumes.iter() {
+        match std::fs::remove_file(volumes.dir.join(&volume.name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Result::Err(e),
+            _ => (),
+        };
+    }
+
+    std::fs::remove_file(manifest)
+}
+
+/// the volumes of a split archive that exist, then its manifest
+pub fn files(manifest: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let volumes = Volumes::open(manifest)?;
+    let mut files: Vec<PathBuf> = (0..volumes.manifest.volumes.len())
+        .map(|n| volumes.path(n))
+        .filter(|p| p.exists())
+        .collect();
+
+    files.push(manifest.to_path_buf());
+
+    Result::Ok(files)
+}
+
+/// the archive a split archive makes up, read across its volumes
+///
+/// every volume read from its start to its end is checked against its hash on
+/// the way, `finish` checks the others
+pub struct Volumes {
+    dir: PathBuf,
+    manifest: Manifest,
+    /// where each volume starts inside the archive
+    starts: Vec<u64>,
+    size: u64,
+    pos: u64,
+    /// the volume being read, and the offset it is at
+    current: Option<(usize, File, u64)>,
+    /// the hash of each volume so far, and how far it got, while it is read without skipping
+    hashing: Vec<Option<(Hasher, u64)>>,
+    verified: Vec<bool>,
+}
+
+impl Volumes {
+    pub fn open(manifest: &Path) -> Result<Self, std::io::Error> {
+        let manifest_data: Manifest = serde_json::from_slice(&std::fs::read(manifest)?)?;
+        let mut starts = Vec::new();
+        let mut size = 0;
+
+        for volume in manifest_data.volumes.iter() {
+            starts.push(size);
+            size += volume.size;
+        }
+
+        let count = manifest_data.volumes.len();
+
+        Result::Ok(Self {
+            dir: manifest.parent().unwrap_or(Path::new("")).to_path_buf(),
+            manifest: manifest_data,
+            starts,
+            size,
+            pos: 0,
+            current: None,
+            hashing: (0..count).map(|_| None).collect(),
+            verified: vec![false; count],
+        })
+    }
+
+    fn path(&self, n: usize) -> PathBuf {
+        self.dir.join(&self.manifest.volumes[n].name)
+    }
+
+    /// opens a volume, rejecting it if its size isn't the one recorded
+    fn open_volume(&self, n: usize) -> Result<File, std::io::Error> {
+        let path = self.path(n);
+
+        let fd = match File::open(&path) {
+            Ok(fd) => fd,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Result::Err(missing(&path)),
+            Err(e) => return Result::Err(e),
+        };
+
+        match fd.metadata()?.len() == self.manifest.volumes[n].size {
+            true => Result::Ok(fd),
+            false => Result::Err(corrupt(&path)),
+        }
+    }
+
+    /// the volume holding the byte at `pos`
+    fn volume_at(&self, pos: u64) -> Option<usize> {
+        match pos < self.size {
+            true => Some(self.starts.partition_point(|s| *s <= pos) - 1),
+            false => None,
+        }
+    }
+
+    /// hashes `data`, read at `offset` into volume `n`, and checks the volume once
+    /// it has been read to its end
+    fn hash(&mut self, n: usize, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        if self.verified[n] {
+            return Result::Ok(());
+        }
+
+        if offset == 0 {
+            self.hashing[n] = Some((Hasher::new(self.manifest.algorithm), 0));
+        }
+
+        let at = match &mut self.hashing[n] {
+            Some((hasher, at)) if *at == offset => {
+                hasher.consume(data);
+                *at += data.len() as u64;
+                *at
+            }
+            _ => {
+                self.hashing[n] = None;
+                return Result::Ok(());
+            }
+        };
+
+        if at == self.manifest.volumes[n].size {
+            self.check(n)?;
+        }
+
+        Result::Ok(())
+    }
+
+    /// compares the hash of a volume read to its end with the manifest
+    fn check(&mut self, n: usize) -> Result<(), std::io::Error> {
+        let (hasher, _) = self.hashing[n].take().expect("the volume has been hashed");
+
+        match hasher.finish() == self.manifest.volumes[n].hash {
+            true => {
+                self.verified[n] = true;
+                Result::Ok(())
+            }
+            false => Result::Err(corrupt(&self.path(n))),
+        }
+    }
+
+    /// checks every volume that hasn't been read to its end yet
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        for n in 0..self.manifest.volumes.len() {
+            if self.verified[n] {
+                continue;
+            }
+
+            let (mut hasher, at) = self.hashing[n]
+                .take()
+                .unwrap_or_else(|| (Hasher::new(self.manifest.algorithm), 0));
+
+            let mut fd = self.open_volume(n)?;
+            let size = self.manifest.volumes[n].size;
+
+            fd.seek(SeekFrom::Start(at))?;
+
+            if at + copy_hashed(&mut fd, None, &mut hasher, size - at)? != size {
+                return Result::Err(corrupt(&self.path(n)));
+            }
+
+            self.hashing[n] = Some((hasher, size));
+            self.check(n)?;
+        }
+
+        Result::Ok(())
+    }
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = match self.volume_at(self.pos) {
+            Some(n) => n,
+            None => return Result::Ok(0),
+        };
+
+        let offset = self.pos - self.starts[n];
+        let left = self.manifest.volumes[n].size - offset;
+        let len = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+
+        let (fd, at) = match &mut self.current {
+            Some((m, fd, at)) if *m == n => (fd, at),
+            _ => {
+                let fd = self.open_volume(n)?;
+                let (_, fd, at) = self.current.insert((n, fd, 0));
+
+                (fd, at)
+            }
+        };
+
+        if *at != offset {
+            fd.seek(SeekFrom::Start(offset))?;
+        }
+
+        let read = fd.read(&mut buf[..len])?;
+        *at = offset + read as u64;
+
+        if read == 0 && len > 0 {
+            return Result::Err(corrupt(&self.path(n)));
+        }
+
+        self.pos += read as u64;
+        self.hash(n, offset, &buf[..read])?;
+
+        Result::Ok(read)
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        let pos = match from {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+
+        match pos {
+            Some(p) => {
+                self.pos = p;
+                Result::Ok(p)
+            }
+            None => Result::Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// a .pncsum archive, either a single file or split into volumes
+pub enum Packed {
+    Single(File),
+    Split(Volumes),
+}
+
+/// opens an archive, or the split archive a .vncsum manifest describes
+pub fn open(path: &Path) -> Result<Packed, std::io::Error> {
+    match is_manifest(path) {
+        true => Result::Ok(Packed::Split(Volumes::open(path)?)),
+        false => Result::Ok(Packed::Single(File::open(path)?)),
+    }
+}
+
+impl Packed {
+    /// checks the volumes of a split archive that weren't read in full
+    pub fn finish(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            Packed::Single(_) => Result::Ok(()),
+            Packed::Split(v) => v.finish(),
+        }
+    }
+}
+
+impl Read for Packed {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Packed::Single(fd) => fd.read(buf),
+            Packed::Split(v) => v.read(buf),
+        }
+    }
+}
+
+impl Seek for Packed {
+    fn seek(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Packed::Single(fd) => fd.seek(from),
+            Packed::Split(v) => v.seek(from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an archive of `len` bytes in a directory of its own, and its data
+    fn archive(test: &str, len: usize) -> (PathBuf, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("ncsum-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let path = dir.join("0123.pncsum");
+        std::fs::write(&path, &data).unwrap();
+
+        (path, data)
+    }
+
+    #[test]
+    fn splits_and_reads_back() {
+        let (path, data) = archive("volume-split", 10_000);
+        let manifest = split(&path, 4096).unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(files(&manifest).unwrap().len(), 4);
+
+        let mut read = Vec::new();
+        let mut packed = open(&manifest).unwrap();
+        packed.read_to_end(&mut read).unwrap();
+        packed.finish().unwrap();
+
+        assert_eq!(read, data);
+
+        // reading out of order still checks every volume
+        let mut volumes = Volumes::open(&manifest).unwrap();
+        let mut tail = vec![0; 100];
+        volumes.seek(SeekFrom::End(-100)).unwrap();
+        volumes.read_exact(&mut tail).unwrap();
+        volumes.finish().unwrap();
+
+        assert_eq!(tail, data[data.len() - 100..]);
+
+        remove(&manifest).unwrap();
+        std::fs::remove_dir(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tells_a_missing_volume() {
+        let (path, _) = archive("volume-missing", 10_000);
+        let manifest = split(&path, 4096).unwrap();
+        let volume = path.with_file_name("0123.pncsum.002");
+
+        std::fs::remove_file(&volume).unwrap();
+
+        let e = Volumes::open(&manifest)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert!(e.to_string().contains("0123.pncsum.002"));
+
+        let e = Volumes::open(&manifest).unwrap().finish().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tells_a_corrupt_volume() {
+        let (path, _) = archive("volume-corrupt", 10_000);
+        let manifest = split(&path, 4096).unwrap();
+        let volume = path.with_file_name("0123.pncsum.003");
+
+        let mut data = std::fs::read(&volume).unwrap();
+        data[10] ^= 0xff;
+        std::fs::write(&volume, &data).unwrap();
+
+        let e = Volumes::open(&manifest)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("0123.pncsum.003\" is corrupt"));
+
+        // one that was cut short is told by its size
+        std::fs::write(&volume, &data[..100]).unwrap();
+
+        let e = Volumes::open(&manifest).unwrap().finish().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! watches a directory with inotify and names or packs the files dropped into it,
+//! once nothing has written to them for a while
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use inotify::{Inotify, WatchMask};
+
+use crate::audit::hash_from_name;
+use crate::format::Format;
+use crate::{is_ncsum_file, list_files, name_file, pack_file};
+
+/// how long the watcher sleeps between looks at its files, at most and at least
+const POLL: Duration = Duration::from_millis(200);
+const MIN_POLL: Duration = Duration::from_millis(20);
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    /// name the files after their hash, like `ncsum name`
+    #[default]
+    Name,
+    /// pack the files into .pncsum archives, like `ncsum pack`
+    Pack,
+}
+
+/// whether a file is left alone, either because it belongs to ncsum or because
+/// it already is named after its hash, like the files the watcher itself renames
+fn is_skipped(file: &Path) -> bool {
+    is_ncsum_file(&file.to_string_lossy()) || hash_from_name(file).is_some()
+}
+
+/// how the watcher describes the files it handles
+pub struct Options<'a> {
+    pub block_size: Option<u64>,
+    pub hmac_key: Option<&'a [u8]>,
+    pub format: Format,
+}
+
+fn run(file: &PathBuf, action: Action, options: &Options) {
+    match action {
+        Action::Name => match name_file(
+            file,
+            options.block_size,
+            None,
+            options.hmac_key,
+            options.format,
+        ) {
+            Ok((info, _)) => println!("{:?} -> {:?}", info.old_name, info.new_name),
+            Err(e) => println!("{e}: {file:?}"),
+        },
+        Action::Pack => match pack_file(
+            file,
+            options.block_size,
+            None,
+            options.hmac_key,
+            options.format,
+            Default::default(),
+            Default::default(),
+        ) {
+            Ok(pname) => println!("{:?}: Created", pname),
+            Err(e) => println!("{e}: {file:?}"),
+        },
+    }
+}
+
+/// handles every file dropped into `dir` after `stable` passes without writes to
+/// it, the files already in `dir` included, until the watch fails
+pub fn watch(
+    dir: &Path,
+    action: Action,
+    stable: Duration,
+    options: &Options,
+) -> Result<(), std::io::Error> {
+    let mut inotify = Inotify::init()?;
+
+    inotify.watches().add(
+        dir,
+        WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+    )?;
+
+    // files waiting to be stable, with the last time they were written to
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut buffer = [0u8; 4096];
+
+    for file in list_files(dir, false)? {
+        if !is_skipped(&file) {
+            pending.insert(file, Instant::now());
+        }
+    }
+
+    println!("watching {:?}", dir);
+
+    loop {
+        match inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                for event in events {
+                    if let Some(name) = event.name {
+                        let file = dir.join(name);
+
+                        if !is_skipped(&file) {
+                            pending.insert(file, Instant::now());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(e) => return Result::Err(e),
+        }
+
+        let ready: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= stable)
+            .map(|(f, _)| f.clone())
+            .collect();
+
+        for file in ready {
+            pending.remove(&file);
+
+            // directories, and files that went away while waiting, are left alone
+            if file.is_file() {
+                run(&file, action, options);
+            }
+        }
+
+        // with no time to wait for, files are still only looked at so often
+        std::thread::sleep(POLL.min(stable).max(MIN_POLL));
+    }
+}
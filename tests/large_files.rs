@@ -0,0 +1,146 @@
+//! packing files larger than a newc archive can hold, made of sparse files so that
+//! they take almost no room until they are packed
+//!
+//! each of these reads and writes several times 4 GiB, so they only run with
+//! `cargo test -- --ignored`
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// one byte more than a newc archive can hold
+const LARGE: u64 = u32::MAX as u64 + 1;
+
+/// a directory of its own for every test
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ncsum-{test}-{}", std::process::id()));
+
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// a sparse file of `size` bytes, ending with a few bytes of data
+fn sparse(path: &Path, size: u64) {
+    let mut fd = File::create(path).unwrap();
+
+    fd.set_len(size).unwrap();
+    fd.seek(SeekFrom::End(-4)).unwrap();
+    fd.write_all(b"tail").unwrap();
+}
+
+fn size(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().len()
+}
+
+fn ncsum(dir: &Path, args: &[&str], stdin: Stdio) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ncsum"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(stdin)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// the names of the files left in `dir` with this suffix
+fn with_suffix(dir: &Path, suffix: &str) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|n| n.ends_with(suffix))
+        .collect()
+}
+
+fn only_archive(dir: &Path) -> String {
+    let archives = with_suffix(dir, ".pncsum");
+    assert_eq!(archives.len(), 1, "{archives:?}");
+
+    archives[0].clone()
+}
+
+/// the archive is tar, holds the whole file, and checks as OK
+fn assert_packed_as_tar(dir: &Path, archive: &str) {
+    let listed = stdout(&ncsum(dir, &["list", archive], Stdio::null()));
+    assert!(listed.contains(": tar"), "{listed}");
+    assert!(listed.contains(&format!("({LARGE} bytes)")), "{listed}");
+
+    let checked = ncsum(dir, &["check", archive], Stdio::null());
+    assert!(checked.status.success(), "{}", stdout(&checked));
+    assert!(stdout(&checked).contains("large.bin: OK"));
+}
+
+#[test]
+#[ignore = "reads and writes more than 4 GiB"]
+fn packs_large_file_as_tar() {
+    let dir = scratch("large-file");
+    sparse(&dir.join("large.bin"), LARGE);
+
+    let packed = ncsum(&dir, &["pack", "large.bin"], Stdio::null());
+    assert!(packed.status.success(), "{}", stdout(&packed));
+
+    let archive = only_archive(&dir);
+    assert_packed_as_tar(&dir, &archive);
+
+    let renamed = ncsum(&dir, &["rename", &archive], Stdio::null());
+    assert!(renamed.status.success(), "{}", stdout(&renamed));
+    assert_eq!(size(&dir.join("large.bin")), LARGE);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore = "reads and writes more than 4 GiB"]
+fn packs_large_stream_as_tar() {
+    let dir = scratch("large-stream");
+    sparse(&dir.join("source"), LARGE);
+
+    let input = Stdio::from(File::open(dir.join("source")).unwrap());
+    let packed = ncsum(&dir, &["pack", "--stdin", "--name", "large.bin"], input);
+    assert!(packed.status.success(), "{}", stdout(&packed));
+    assert!(with_suffix(&dir, ".tncsum").is_empty());
+
+    let archive = only_archive(&dir);
+    assert_packed_as_tar(&dir, &archive);
+
+    let extracted = ncsum(&dir, &["extract", "-d", "out", &archive], Stdio::null());
+    assert!(extracted.status.success(), "{}", stdout(&extracted));
+    assert_eq!(size(&dir.join("out").join("large.bin")), LARGE);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore = "reads more than 4 GiB"]
+fn refuses_large_file_in_cpio() {
+    let dir = scratch("large-cpio");
+    sparse(&dir.join("large.bin"), LARGE);
+
+    let packed = ncsum(
+        &dir,
+        &["pack", "--container", "cpio", "large.bin"],
+        Stdio::null(),
+    );
+    assert!(!packed.status.success());
+    assert!(stdout(&packed).contains("too large for a newc archive"));
+
+    // nothing is left behind, and the file is kept
+    assert_eq!(with_suffix(&dir, "ncsum"), Vec::<String>::new());
+    assert_eq!(size(&dir.join("large.bin")), LARGE);
+
+    let input = Stdio::from(File::open(dir.join("large.bin")).unwrap());
+    let packed = ncsum(
+        &dir,
+        &["pack", "--stdin", "--name", "s.bin", "--container", "cpio"],
+        input,
+    );
+    assert!(!packed.status.success());
+    assert_eq!(with_suffix(&dir, "ncsum"), Vec::<String>::new());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}